@@ -1,56 +1,464 @@
 #![allow(dead_code)]
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_repr::*;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Debug, Parser)]
 struct Arguments {
     #[clap(subcommand)]
     command: SubCommand,
+
+    /// Show debug logs and the number of API requests made. Must be given before the subcommand.
+    #[clap(short = 'v', long = "verbose", conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Show only error logs. Must be given before the subcommand.
+    #[clap(short = 'q', long = "quiet")]
+    quiet: bool,
+
+    /// Print errors to stderr as a JSON object like {"error":"rate_limited","status":429,"message":"..."}.
+    /// Must be given before the subcommand.
+    #[clap(long = "json-errors")]
+    json_errors: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum SubCommand {
     /// Execute Route
-    Route(RouteArgs),
+    Route(Box<RouteArgs>),
+    /// Resolve a place name to coordinates
+    Geocode(GeocodeArgs),
+    /// Compute travel times and distances between all pairs of points
+    Matrix(MatrixArgs),
+    /// Compare two routes with different priority or toll way settings
+    Compare(CompareArgs),
 }
 
 #[derive(Debug, Parser)]
-struct RouteArgs {
-    /// Origin. Must be "longitude,latitude" format.
+struct CompareArgs {
+    /// Origin. Must be "longitude,latitude" or "name:<place name>" format.
     #[clap(short = 'f', long = "from")]
     from: String,
 
-    /// Destination. Must be "longitude,latitude" format.
+    /// Destination. Must be "longitude,latitude" or "name:<place name>" format.
     #[clap(short = 't', long = "to")]
     to: String,
 
-    /// Via. Must be "longitude,latitude" format.
+    /// Priority of route A.
+    #[clap(long = "priority-a", value_enum, default_value_t = Priority::Normal)]
+    priority_a: Priority,
+
+    /// Priority of route B.
+    #[clap(long = "priority-b", value_enum, default_value_t = Priority::DistanceFirst)]
+    priority_b: Priority,
+
+    /// Toll way preference of route A.
+    #[clap(long = "tollway-a", value_enum, default_value = None)]
+    tollway_a: Option<Tollway>,
+
+    /// Toll way preference of route B.
+    #[clap(long = "tollway-b", value_enum, default_value = None)]
+    tollway_b: Option<Tollway>,
+}
+
+#[derive(Debug, Parser)]
+struct MatrixArgs {
+    /// Points as "lon,lat;lon,lat;..." or a file with one "lon,lat" per line. Points are
+    /// separated by ';' or whitespace because each point already contains a comma.
+    points: String,
+
+    /// Number of route requests running at the same time.
+    #[clap(short = 'c', long = "concurrency", default_value_t = 4)]
+    concurrency: usize,
+
+    /// Output to file.
+    #[clap(short = 'o', long = "output", default_value = None)]
+    file: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+struct GeocodeArgs {
+    /// Place name or address.
+    query: String,
+}
+
+#[derive(Debug, Parser)]
+struct RouteArgs {
+    /// Origin. Must be "longitude,latitude" or "name:<place name>" format.
+    #[clap(short = 'f', long = "from", required_unless_present = "replay")]
+    from: Option<String>,
+
+    /// Destination. Must be "longitude,latitude" or "name:<place name>" format.
+    #[clap(short = 't', long = "to", required_unless_present = "replay")]
+    to: Option<String>,
+
+    /// Via. Must be "longitude,latitude[,type[,priority]]" format, delimited by '|'.
     #[clap(short = 'v', long = "via", default_value = None)]
     via: Option<String>,
 
-    /// Departure datetime. "yyyyMMdd_HHmmss.
+    /// Starting angle 0 ~ 359.
+    #[clap(long = "start-angle", default_value = None)]
+    start_angle: Option<i16>,
+
+    /// Car type for toll price.
+    #[clap(long = "car-type", value_enum, default_value = None)]
+    car_type: Option<CarType>,
+
+    /// Toll to calculate.
+    #[clap(long = "toll-target", value_enum, default_value = None)]
+    toll_target: Option<TollTarget>,
+
+    /// Vehicle type for road regulations.
+    #[clap(long = "vehicle-type", value_enum, default_value = None)]
+    vehicle_type: Option<VehicleType>,
+
+    /// Speed on normal way(km/h).
+    #[clap(long = "normal-speed", default_value = None)]
+    normal_speed: Option<f32>,
+
+    /// Speed on highway(km/h).
+    #[clap(long = "highway-speed", default_value = None)]
+    highway_speed: Option<f32>,
+
+    /// Speed on toll way(km/h).
+    #[clap(long = "tollway-speed", default_value = None)]
+    tollway_speed: Option<f32>,
+
+    /// Speed on ferry(km/h).
+    #[clap(long = "ferry-speed", default_value = None)]
+    ferry_speed: Option<f32>,
+
+    /// Carrying dangerous cargo.
+    #[clap(long = "dangerous-cargo")]
+    dangerous_cargo: bool,
+
+    /// Apply daytime-only restrictions.
+    #[clap(long = "daytime-restriction")]
+    daytime_restriction: bool,
+
+    /// Apply general road regulations.
+    #[clap(long = "restrict-general-road")]
+    restrict_general_road: bool,
+
+    /// Search a travel (sightseeing) route.
+    #[clap(long = "travel", alias = "scenic")]
+    travel: bool,
+
+    /// Departure datetime. "yyyyMMdd_HHmmss", "now" or relative to now like "+30m", "+2h".
     #[clap(short = 'd', long = "date", default_value = None)]
     date: Option<String>,
 
-    /// Output to file.
+    /// Area to avoid. Must be "lon,lat;lon,lat;lon,lat;..." format. Can be repeated.
+    #[clap(long = "avoid-area")]
+    avoid_area: Vec<String>,
+
+    /// Area allowed to pass through. Must be "lon,lat;lon,lat;lon,lat;..." format. Can be repeated.
+    #[clap(long = "through-area")]
+    through_area: Vec<String>,
+
+    /// Enforce one-way restrictions. The API default is off.
+    #[clap(long = "respect-oneway", conflicts_with = "ignore_oneway")]
+    respect_oneway: bool,
+
+    /// Ignore one-way restrictions explicitly. The API default is off.
+    #[clap(long = "ignore-oneway")]
+    ignore_oneway: bool,
+
+    /// Avoid U-turns. Sent along with --respect-oneway/--ignore-oneway as given.
+    #[clap(long = "avoid-uturn", conflicts_with = "allow_uturn")]
+    avoid_uturn: bool,
+
+    /// Allow U-turns. Sent along with --respect-oneway/--ignore-oneway as given.
+    #[clap(long = "allow-uturn")]
+    allow_uturn: bool,
+
+    /// Show distances in miles and speeds in mph in the summary/highlights output.
+    #[clap(long = "imperial")]
+    imperial: bool,
+
+    /// Precision of "--format polyline".
+    #[clap(long = "polyline-precision", default_value_t = 5)]
+    polyline_precision: u32,
+
+    /// Number of shape points to average elevations over for the ascent, descent and
+    /// max grade in "--format summary". 1 disables smoothing.
+    #[clap(long = "grade-window", default_value_t = 1)]
+    grade_window: usize,
+
+    /// Simplify the route geometry with the given tolerance(m) before output.
+    #[clap(long = "simplify", default_value = None)]
+    simplify: Option<f64>,
+
+    /// Output to file. With a text format (summary, sections, toll-compare or --highlights)
+    /// the file gets the JSON route (see --pretty) and the text is printed to stdout.
+    /// Other formats are written to the file instead of stdout.
     #[clap(short = 'o', long = "output", default_value = None)]
     file: Option<String>,
+
+    /// Pretty-print JSON output.
+    #[clap(short = 'p', long = "pretty")]
+    pretty: bool,
+
+    /// Print the request URL and headers (API key redacted) without sending it.
+    #[clap(long = "dry-run")]
+    dry_run: bool,
+
+    /// Load a saved route JSON instead of calling the API, and output it with the given
+    /// options. Request options are ignored; "--format toll-compare" shows only the saved toll.
+    #[clap(long = "replay", conflicts_with_all = ["dry_run", "raw"])]
+    replay: Option<String>,
+
+    /// Write the response body as is, without parsing it into a route.
+    #[clap(
+        long = "raw",
+        conflicts_with_all = [
+            "format", "highlights", "simplify", "pretty", "no_shape", "no_guide", "fields"
+        ]
+    )]
+    raw: bool,
+
+    /// Drop shape data (shape, shapeInfo, shapePoints, shapeIndex*) from the output.
+    /// calcroute has no request parameter for this, so it is removed after download.
+    #[clap(long = "no-shape")]
+    no_shape: bool,
+
+    /// Drop the guide list, leaving the summary. calcroute has no request parameter for
+    /// this, so it is removed after download.
+    #[clap(long = "no-guide")]
+    no_guide: bool,
+
+    /// Output format.
+    #[clap(long = "format", value_enum, default_value_t = Format::Json)]
+    format: Format,
+
+    /// Keep only these comma separated dotted paths in the JSON output,
+    /// e.g. "summary.totalTravelTime,guide.guideInfo.guideCrossing.name".
+    #[clap(long = "fields", default_value = None)]
+    fields: Option<String>,
+
+    /// Print only highway entrances/exits, service areas and ferry terminals.
+    #[clap(long = "highlights", conflicts_with = "format")]
+    highlights: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum Format {
+    /// Parsed route as JSON
+    Json,
+    /// API response as XML (passed through verbatim)
+    Xml,
+    /// Self-contained Leaflet map
+    Html,
+    /// Human readable summary
+    Summary,
+    /// Encoded polyline of the route geometry
+    Polyline,
+    /// Travel time of each leg between via points
+    Sections,
+    /// Cash and ETC toll side by side
+    TollCompare,
+    /// KML for Google Earth
+    Kml,
+}
+
+impl Format {
+    /// Formats meant for reading on the terminal rather than saving.
+    fn is_text(&self) -> bool {
+        matches!(
+            self,
+            Format::Summary | Format::Sections | Format::TollCompare
+        )
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let main_args = Arguments::parse();
     //println!("{:?}", args);
-    match main_args.command {
-        SubCommand::Route(route_args) => handle_route(route_args).await,
+    let level = if main_args.verbose {
+        log::LevelFilter::Debug
+    } else if main_args.quiet {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Warn
+    };
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module(env!("CARGO_CRATE_NAME"), level)
+        .init();
+    let client = ApiClient::new(http_client());
+    let result = match main_args.command {
+        SubCommand::Route(route_args) => handle_route(*route_args, &client).await,
+        SubCommand::Geocode(geocode_args) => handle_geocode(geocode_args, &client).await,
+        SubCommand::Matrix(matrix_args) => handle_matrix(matrix_args, &client).await,
+        SubCommand::Compare(compare_args) => handle_compare(compare_args, &client).await,
+    };
+    if main_args.verbose {
+        eprintln!("{}", client.stats.to_text());
+    }
+    if let Err(e) = result {
+        if main_args.json_errors {
+            eprintln!("{}", error_json(&e));
+        } else {
+            eprintln!("Error: {:?}", e);
+        }
+        std::process::exit(exit_code(&e));
+    }
+}
+
+/// Failure classes reported with distinct exit codes.
+#[derive(Debug)]
+enum AppError {
+    /// invalid coordinates, date or other parameters
+    InvalidInput(String),
+    /// 401 or 403
+    Auth {
+        status: StatusCode,
+        url: String,
+        detail: String,
+    },
+    /// 429
+    RateLimited { url: String, detail: String },
+    /// any other non-OK status
+    Http {
+        status: StatusCode,
+        url: String,
+        detail: String,
+    },
+    /// response body could not be parsed
+    Parse(String),
+    /// the response has a non-OK `status`, e.g. no route was found
+    RouteStatus(String),
+}
+
+impl AppError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::InvalidInput(_) => 2,
+            AppError::Auth { .. } => 3,
+            AppError::RateLimited { .. } => 4,
+            AppError::Http { .. } => 5,
+            AppError::Parse(_) => 6,
+            AppError::RouteStatus(_) => 7,
+        }
+    }
+
+    /// Error name used in "--json-errors" output.
+    fn kind(&self) -> &'static str {
+        match self {
+            AppError::InvalidInput(_) => "invalid_input",
+            AppError::Auth { .. } => "auth",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Http { .. } => "http",
+            AppError::Parse(_) => "parse",
+            AppError::RouteStatus(_) => "route_status",
+        }
+    }
+
+    fn status(&self) -> Option<StatusCode> {
+        match self {
+            AppError::Auth { status, .. } | AppError::Http { status, .. } => Some(*status),
+            AppError::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AppError::InvalidInput(msg) => write!(f, "invalid input: {}", msg),
+            AppError::Auth {
+                status,
+                url,
+                detail,
+            } => write!(
+                f,
+                "authentication failed: {} {}, check RAPID_API_KEY: {}",
+                status, url, detail
+            ),
+            AppError::RateLimited { url, detail } => {
+                write!(f, "rate limited: {}: {}", url, detail)
+            }
+            AppError::Http {
+                status,
+                url,
+                detail,
+            } => write!(f, "request failed: {} {}: {}", status, url, detail),
+            AppError::Parse(msg) => write!(f, "failed to parse response: {}", msg),
+            AppError::RouteStatus(status) => write!(f, "route search failed: {}", status),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Exit code for an error. Errors other than `AppError` exit with 1.
+fn exit_code(e: &anyhow::Error) -> i32 {
+    e.downcast_ref::<AppError>()
+        .map(AppError::exit_code)
+        .unwrap_or(1)
+}
+
+/// Error body returned by RapidAPI/MapFan.
+#[derive(Deserialize, Debug)]
+struct ApiError {
+    #[serde(alias = "error")]
+    message: String,
+}
+
+/// Machine readable form of an error for "--json-errors".
+fn error_json(e: &anyhow::Error) -> serde_json::Value {
+    let app_error = e.downcast_ref::<AppError>();
+    let mut json = serde_json::json!({
+        "error": app_error.map(|e| e.kind()).unwrap_or("other"),
+        "message": format!("{:#}", e),
+    });
+    if let Some(status) = app_error.and_then(|e| e.status()) {
+        json["status"] = status.as_u16().into();
+    }
+    json
+}
+
+/// Describe an error body, preferring the message of a known `ApiError` shape.
+fn describe_error_body(body: &str) -> String {
+    match serde_json::from_str::<ApiError>(body) {
+        Ok(e) => format!("API error: {}", e.message),
+        Err(_) => body.trim().to_string(),
+    }
+}
+
+/// Pass an OK response through, or read the body of a non-OK one into an `AppError`.
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    let status = res.status();
+    if status == StatusCode::OK {
+        return Ok(res);
     }
+    let url = res.url().to_string();
+    let detail = describe_error_body(&res.text().await.unwrap_or_default());
+    Err(match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AppError::Auth {
+            status,
+            url,
+            detail,
+        },
+        StatusCode::TOO_MANY_REQUESTS => AppError::RateLimited { url, detail },
+        _ => AppError::Http {
+            status,
+            url,
+            detail,
+        },
+    }
+    .into())
 }
 
+#[derive(Clone)]
 struct RequestHeader {
     /// Rapid API key
     api_key: String,
@@ -59,27 +467,213 @@ struct RequestHeader {
     api_host: String,
 }
 
+impl std::fmt::Debug for RequestHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RequestHeader")
+            .field("api_key", &redact_key(&self.api_key))
+            .field("api_host", &self.api_host)
+            .finish()
+    }
+}
+
+/// Mask an API key except for the last 4 characters.
+fn redact_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let visible = if chars.len() > 8 { 4 } else { 0 };
+    let masked = "*".repeat(chars.len() - visible);
+    masked + &chars[chars.len() - visible..].iter().collect::<String>()
+}
+
+const ROUTE_HOST: &str = "mapfanapi-route.p.rapidapi.com";
+
 impl RequestHeader {
-    fn new() -> Self {
-        let api_key = env::var("RAPID_API_KEY").expect("RAPID_API_KEY is not set");
-        Self {
+    fn new() -> Result<Self> {
+        Self::new_with_host(ROUTE_HOST)
+    }
+
+    fn new_with_host(api_host: &str) -> Result<Self> {
+        let api_key = env::var("RAPID_API_KEY")
+            .map_err(|_| AppError::InvalidInput("RAPID_API_KEY is not set".to_string()))?;
+        Ok(Self {
             api_key,
-            api_host: "mapfanapi-route.p.rapidapi.com".to_string(),
-        }
+            api_host: api_host.to_string(),
+        })
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct Position {
-    longitude: f32,
-    latitude: f32,
+    longitude: f64,
+    latitude: f64,
     // type
 }
 
+impl std::str::FromStr for Position {
+    type Err = anyhow::Error;
+
+    /// Parse "longitude,latitude" format.
+    fn from_str(s: &str) -> Result<Self> {
+        let v = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| {
+                AppError::InvalidInput(format!("input must be a float number: {}, {}", s, e))
+            })?;
+        anyhow::ensure!(
+            v.len() == 2,
+            AppError::InvalidInput(format!(
+                "invalid position parameter, it must be 'lon,lat' format: {}",
+                s
+            ))
+        );
+        Ok(Position {
+            longitude: v[0],
+            latitude: v[1],
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ViaPoint {
+    position: Position,
+    via_type: Option<u8>,
+    priority: Option<u8>,
+}
+
+impl ViaPoint {
+    /// "lon,lat,type,priority" format. Trailing empty values are omitted.
+    fn to_param(&self) -> String {
+        let mut s = self.position.to_param();
+        match (self.via_type, self.priority) {
+            (None, None) => {}
+            (Some(t), None) => s += &format!(",{}", t),
+            (t, Some(p)) => {
+                s += &format!(",{},{}", t.map(|t| t.to_string()).unwrap_or_default(), p)
+            }
+        }
+        s
+    }
+}
+
+impl std::str::FromStr for ViaPoint {
+    type Err = anyhow::Error;
+
+    /// Parse "lon,lat[,type[,priority]]" format.
+    fn from_str(s: &str) -> Result<Self> {
+        let v: Vec<&str> = s.split(',').collect();
+        anyhow::ensure!(
+            (2..=4).contains(&v.len()),
+            AppError::InvalidInput(format!(
+                "invalid via parameter, it must be 'lon,lat,type,priority' format: {}",
+                s
+            ))
+        );
+        let optional = |i: usize| -> Result<Option<u8>> {
+            match v.get(i).map(|x| x.trim()) {
+                None | Some("") => Ok(None),
+                Some(x) => Ok(Some(x.parse().map_err(|e| {
+                    AppError::InvalidInput(format!("invalid via parameter: {}, {}", s, e))
+                })?)),
+            }
+        };
+        Ok(ViaPoint {
+            position: v[..2].join(",").parse()?,
+            via_type: optional(2)?,
+            priority: optional(3)?,
+        })
+    }
+}
+
+const DATE_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// Resolve "now", "+30m", "+2h" or "yyyyMMdd_HHmmss" into "yyyyMMdd_HHmmss".
+fn resolve_date(s: &str) -> Result<String> {
+    resolve_date_at(s, chrono::Local::now().naive_local())
+}
+
+fn resolve_date_at(s: &str, now: chrono::NaiveDateTime) -> Result<String> {
+    let invalid = || {
+        AppError::InvalidInput(format!(
+            "invalid date, it must be 'yyyyMMdd_HHmmss', 'now' or '+<n>m'/'+<n>h' format: {}",
+            s
+        ))
+    };
+    let date = if s == "now" {
+        now
+    } else if let Some(offset) = s.strip_prefix('+') {
+        let unit_at = offset.char_indices().last().map(|(i, _)| i).unwrap_or(0);
+        let (n, unit) = offset.split_at(unit_at);
+        // digits only, so that "+-5m" or "++5m" are not taken as offsets
+        if n.is_empty() || !n.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid().into());
+        }
+        let n: i64 = n.parse().map_err(|_| invalid())?;
+        let duration = match unit {
+            "m" => chrono::Duration::try_minutes(n),
+            "h" => chrono::Duration::try_hours(n),
+            _ => return Err(invalid().into()),
+        };
+        duration
+            .and_then(|d| now.checked_add_signed(d))
+            .ok_or_else(invalid)?
+    } else {
+        chrono::NaiveDateTime::parse_from_str(s, DATE_FORMAT).map_err(|_| invalid())?
+    };
+    Ok(date.format(DATE_FORMAT).to_string())
+}
+
+impl Position {
+    /// "lon,lat" with a fixed number of decimals so that requests are reproducible.
+    fn to_param(self) -> String {
+        format!("{:.6},{:.6}", self.longitude, self.latitude)
+    }
+}
+
+/// Parse a polygon of "lon,lat;lon,lat;lon,lat;..." format.
+fn parse_polygon(s: &str) -> Result<Vec<Position>> {
+    s.split(';').map(|p| p.parse()).collect()
+}
+
+/// Serialize a polygon as "lon,lat,lon,lat,..." after checking it has at least three points.
+fn format_polygon(polygon: &[Position]) -> Result<String> {
+    anyhow::ensure!(
+        polygon.len() >= 3,
+        AppError::InvalidInput(format!(
+            "polygon must have at least three points: {:?}",
+            polygon
+        ))
+    );
+    Ok(polygon
+        .iter()
+        .map(|p| p.to_param())
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+fn check_speed(name: &str, speed: f32) -> Result<f32> {
+    anyhow::ensure!(
+        speed > 0.0,
+        AppError::InvalidInput(format!("{} speed must be positive: {}", name, speed))
+    );
+    Ok(speed)
+}
+
+/// Append a polygon to the '|' delimited area list.
+fn push_area(areas: &mut Option<String>, polygon: &[Position]) -> Result<()> {
+    let polygon = format_polygon(polygon)?;
+    *areas = Some(match areas.take() {
+        Some(a) => format!("{}|{}", a, polygon),
+        None => polygon,
+    });
+    Ok(())
+}
+
 impl CalcRouteRequestParam {
     fn new(start: Position, destination: Position) -> Self {
         Self {
-            start: format!("{},{}", start.longitude, start.latitude),
-            destination: format!("{},{}", destination.longitude, destination.latitude),
+            start: start.to_param(),
+            destination: destination.to_param(),
             ..Default::default()
         }
     }
@@ -91,8 +685,33 @@ impl CalcRouteRequestParam {
         }
     }
 
-    fn via(&mut self, via: String) -> &mut Self {
-        self.via = Some(via);
+    /// Starting angle 0 ~ 359.
+    fn start_angle(&mut self, angle: i16) -> Result<&mut Self> {
+        anyhow::ensure!(
+            (0..=359).contains(&angle),
+            AppError::InvalidInput(format!("start angle must be between 0 and 359: {}", angle))
+        );
+        self.startangle = Some(angle);
+        Ok(self)
+    }
+
+    /// Via points in "lon,lat,type,priority|lon,lat,type,priority|..." format.
+    fn via(&mut self, via: String) -> Result<&mut Self> {
+        let points = via
+            .split('|')
+            .map(|p| p.parse())
+            .collect::<Result<Vec<ViaPoint>>>()?;
+        Ok(self.via_points(points))
+    }
+
+    fn via_points(&mut self, points: Vec<ViaPoint>) -> &mut Self {
+        self.via = Some(
+            points
+                .iter()
+                .map(|p| p.to_param())
+                .collect::<Vec<_>>()
+                .join("|"),
+        );
         self
     }
 
@@ -111,61 +730,258 @@ impl CalcRouteRequestParam {
         self
     }
 
+    /// Speed on normal way(km/h).
+    fn normal_speed(&mut self, speed: f32) -> Result<&mut Self> {
+        self.normalspeed = Some(check_speed("normal", speed)?);
+        Ok(self)
+    }
+
+    /// Speed on highway(km/h).
+    fn highway_speed(&mut self, speed: f32) -> Result<&mut Self> {
+        self.highwayspeed = Some(check_speed("highway", speed)?);
+        Ok(self)
+    }
+
+    /// Speed on toll way(km/h).
+    fn tollway_speed(&mut self, speed: f32) -> Result<&mut Self> {
+        self.tollwayspeed = Some(check_speed("tollway", speed)?);
+        Ok(self)
+    }
+
+    /// Speed on ferry(km/h).
+    fn ferry_speed(&mut self, speed: f32) -> Result<&mut Self> {
+        self.ferryspeed = Some(check_speed("ferry", speed)?);
+        Ok(self)
+    }
+
+    /// Carrying dangerous cargo.
+    fn danger(&mut self, danger: bool) -> &mut Self {
+        self.danger = Some(danger as u8);
+        self
+    }
+
+    /// Apply daytime-only restrictions.
+    fn daytime(&mut self, daytime: bool) -> &mut Self {
+        self.daytime = Some(daytime as u8);
+        self
+    }
+
+    /// Apply general road regulations.
+    fn general_road(&mut self, generalroad: bool) -> &mut Self {
+        self.generalroad = Some(generalroad as u8);
+        self
+    }
+
+    fn priority(&mut self, priority: Priority) -> &mut Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    fn tollway(&mut self, tollway: Tollway) -> &mut Self {
+        self.tollway = Some(tollway);
+        self
+    }
+
+    fn toll_target(&mut self, tolltarget: TollTarget) -> &mut Self {
+        self.tolltarget = Some(tolltarget);
+        self
+    }
+
+    /// Search a travel (sightseeing) route. It is sent independently of `priority`; how the
+    /// search combines the two is up to the API.
+    fn travel(&mut self, travel: bool) -> &mut Self {
+        self.travel = Some(travel.into());
+        self
+    }
+
+    /// Enforce one-way restrictions. The API default is off.
+    fn regulations(&mut self, regulations: bool) -> &mut Self {
+        self.regulations = Some(regulations.into());
+        self
+    }
+
+    fn etc(&mut self, etc: OnOff) -> &mut Self {
+        self.etc = Some(etc);
+        self
+    }
+
     fn date(&mut self, date: String) -> &mut Self {
         // TODO: check date format yyyyMMdd_HHmmss
         self.date = Some(date);
         self
     }
 
-    fn to_params(self) -> Vec<(String, String)> {
+    /// Add an area the route must not pass through.
+    fn impassable_area(&mut self, polygon: &[Position]) -> Result<&mut Self> {
+        push_area(&mut self.impassablearea, polygon)?;
+        Ok(self)
+    }
+
+    /// Add an area the route is allowed to pass through.
+    fn passable_area(&mut self, polygon: &[Position]) -> Result<&mut Self> {
+        push_area(&mut self.passablearea, polygon)?;
+        Ok(self)
+    }
+
+    /// Avoid U-turns on the way. It is sent independently of `regulations`; how the search
+    /// weighs the two is up to the API.
+    fn uturn_avoid(&mut self, avoid: bool) -> &mut Self {
+        self.uturnavoid = Some(avoid.into());
+        self
+    }
+
+    /// Allow choosing a U-turn on the way. Like `uturn_avoid`, independent of `regulations`.
+    fn uturn(&mut self, allow: bool) -> &mut Self {
+        self.uturn = Some(allow.into());
+        self
+    }
+
+    fn format(&mut self, fmt: OutputFormat) -> &mut Self {
+        self.fmt = Some(fmt);
+        self
+    }
+
+    /// Check that the request has either both start and destination or a route result id.
+    fn validate(&self) -> Result<()> {
+        let has_points = !self.start.is_empty() && !self.destination.is_empty();
+        let has_any_point = !self.start.is_empty() || !self.destination.is_empty();
+        match &self.routeresultid {
+            Some(_) => anyhow::ensure!(
+                !has_any_point,
+                AppError::InvalidInput(
+                    "either start and destination or routeresultid must be set, not both"
+                        .to_string()
+                )
+            ),
+            None => anyhow::ensure!(
+                has_points,
+                AppError::InvalidInput(
+                    "both start and destination, or routeresultid must be set".to_string()
+                )
+            ),
+        }
+        Ok(())
+    }
+
+    fn to_params(&self) -> Vec<(String, String)> {
         let mut p = vec![];
-        if let Some(result_id) = self.routeresultid {
-            p.push(("routeresultid".to_string(), result_id));
+        if let Some(result_id) = &self.routeresultid {
+            p.push(("routeresultid".to_string(), result_id.clone()));
         } else {
-            p.push(("start".to_string(), self.start));
-            p.push(("destination".to_string(), self.destination));
-            if let Some(via) = self.via {
+            p.push(("start".to_string(), self.start.clone()));
+            p.push(("destination".to_string(), self.destination.clone()));
+            if let Some(startangle) = self.startangle {
+                p.push(("startangle".to_string(), startangle.to_string()));
+            }
+            if let Some(via) = &self.via {
                 p.push((
                     "via".to_string(),
-                    via
+                    via.clone()
+                ));
+            }
+            if let Some(priority) = &self.priority {
+                p.push((
+                    "priority".to_string(),
+                    serde_json::to_string(priority).unwrap(),
+                ));
+            }
+            if let Some(tollway) = &self.tollway {
+                p.push((
+                    "tollway".to_string(),
+                    serde_json::to_string(tollway).unwrap(),
                 ));
             }
-            if let Some(cartype) = self.cartype {
+            if let Some(tolltarget) = &self.tolltarget {
+                p.push((
+                    "tolltarget".to_string(),
+                    serde_json::to_string(tolltarget).unwrap(),
+                ));
+            }
+            if let Some(cartype) = &self.cartype {
                 p.push((
                     "cartype".to_string(),
-                    serde_json::to_string(&cartype).unwrap(),
+                    serde_json::to_string(cartype).unwrap(),
                 ));
             }
-            if let Some(date) = self.date {
+            if let Some(date) = &self.date {
                 p.push((
                     "date".to_string(),
-                    date,
+                    date.clone(),
                 ));
             }
-            if let Some(resulttype) = self.resulttype {
+            for (name, speed) in [
+                ("normalspeed", self.normalspeed),
+                ("highwayspeed", self.highwayspeed),
+                ("tollwayspeed", self.tollwayspeed),
+                ("ferryspeed", self.ferryspeed),
+            ] {
+                if let Some(speed) = speed {
+                    p.push((name.to_string(), speed.to_string()));
+                }
+            }
+            if let Some(etc) = &self.etc {
+                p.push(("etc".to_string(), serde_json::to_string(etc).unwrap()));
+            }
+            if let Some(resulttype) = &self.resulttype {
                 p.push((
                     "resulttype".to_string(),
-                    serde_json::to_string(&resulttype).unwrap(),
+                    serde_json::to_string(resulttype).unwrap(),
                 ));
             }
-            if let Some(vehicletype) = self.vehicletype {
+            if let Some(vehicletype) = &self.vehicletype {
                 p.push((
                     "vehicletype".to_string(),
-                    serde_json::to_string(&vehicletype).unwrap(),
+                    serde_json::to_string(vehicletype).unwrap(),
                 ));
             }
-            if let Some(tollroad) = self.tollroad {
+            for (name, value) in [
+                ("danger", self.danger),
+                ("daytime", self.daytime),
+                ("generalroad", self.generalroad),
+            ] {
+                if let Some(value) = value {
+                    p.push((name.to_string(), value.to_string()));
+                }
+            }
+            if let Some(tollroad) = &self.tollroad {
                 p.push((
                     "tollroad".to_string(),
-                    serde_json::to_string(&tollroad).unwrap(),
+                    serde_json::to_string(tollroad).unwrap(),
+                ));
+            }
+            if let Some(regulations) = &self.regulations {
+                p.push((
+                    "regulations".to_string(),
+                    serde_json::to_string(regulations).unwrap(),
+                ));
+            }
+            if let Some(travel) = &self.travel {
+                p.push(("travel".to_string(), serde_json::to_string(travel).unwrap()));
+            }
+            if let Some(uturnavoid) = &self.uturnavoid {
+                p.push((
+                    "uturnavoid".to_string(),
+                    serde_json::to_string(uturnavoid).unwrap(),
                 ));
             }
+            if let Some(uturn) = &self.uturn {
+                p.push(("uturn".to_string(), serde_json::to_string(uturn).unwrap()));
+            }
+            if let Some(passablearea) = &self.passablearea {
+                p.push(("passablearea".to_string(), passablearea.clone()));
+            }
+            if let Some(impassablearea) = &self.impassablearea {
+                p.push(("impassablearea".to_string(), impassablearea.clone()));
+            }
+        }
+        if let Some(fmt) = &self.fmt {
+            p.push(("fmt".to_string(), fmt.as_param().to_string()));
         }
         p
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 struct CalcRouteRequestParam {
     start: String,
 
@@ -200,9 +1016,9 @@ struct CalcRouteRequestParam {
     #[serde(skip_serializing_if = "Option::is_none")]
     etc: Option<OnOff>,
 
-    /// normal + etc discount: 1
+    /// toll to calculate
     #[serde(skip_serializing_if = "Option::is_none")]
-    tolltarget: Option<u8>,
+    tolltarget: Option<TollTarget>,
 
     /// for toll price
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -267,12 +1083,23 @@ struct CalcRouteRequestParam {
     /// travel route: 1
     #[serde(skip_serializing_if = "Option::is_none")]
     travel: Option<OnOff>,
-    //passablearea: Option<String>,
-    //impassablearea: Option<String>,
-    /// avoid Uturn
-    //uturnavoid: Option<u8>,
-    /// choose Uturn
-    //uturn: Option<u8>,
+
+    /// passable area 'lon,lat,lon,lat,...|lon,lat,...'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passablearea: Option<String>,
+
+    /// impassable area 'lon,lat,lon,lat,...|lon,lat,...'
+    #[serde(skip_serializing_if = "Option::is_none")]
+    impassablearea: Option<String>,
+
+    /// avoid Uturn: 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uturnavoid: Option<OnOff>,
+
+    /// choose Uturn: 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uturn: Option<OnOff>,
+
     /// ID of this request
     //routeid: Option<String>,
 
@@ -288,7 +1115,7 @@ struct CalcRouteRequestParam {
     fmt: Option<OutputFormat>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 enum OutputFormat {
     #[serde(rename = "json")]
     Json,
@@ -296,7 +1123,16 @@ enum OutputFormat {
     Xml,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+impl OutputFormat {
+    fn as_param(&self) -> &str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Xml => "xml",
+        }
+    }
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug, ValueEnum)]
 #[repr(u16)]
 enum Priority {
     Normal = 0,
@@ -310,7 +1146,7 @@ enum Priority {
     WalkerLessSteps = 103,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug, ValueEnum)]
 #[repr(u8)]
 enum Tollway {
     Normal = 0,
@@ -319,7 +1155,7 @@ enum Tollway {
     Never = 3,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug)]
 #[repr(u8)]
 enum Ferry {
     Normal = 0,
@@ -328,10 +1164,20 @@ enum Ferry {
     Never = 3,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug, ValueEnum)]
 #[repr(u8)]
-enum CarType {
+enum TollTarget {
+    /// normal toll
+    Normal = 0,
+    /// normal + etc discount
+    NormalPlusEtcDiscount = 1,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug, ValueEnum)]
+#[repr(u8)]
+enum CarType {
     /// 軽自動車
+    #[value(name = "kei")]
     Small = 0,
     /// 普通車
     Normal = 1,
@@ -340,10 +1186,11 @@ enum CarType {
     /// 大型車
     Big = 3,
     /// 特大車
+    #[value(name = "super-big")]
     SuperBig = 4,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug, ValueEnum)]
 #[repr(u8)]
 enum VehicleType {
     None = 0,
@@ -355,13 +1202,23 @@ enum VehicleType {
     BigSpecial = 11,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug)]
 #[repr(u8)]
 enum OnOff {
     Off = 0,
     On = 1,
 }
 
+impl From<bool> for OnOff {
+    fn from(b: bool) -> Self {
+        if b {
+            OnOff::On
+        } else {
+            OnOff::Off
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct RouteResult {
     #[serde(rename = "routeId")]
@@ -371,22 +1228,656 @@ struct RouteResult {
     route_result_id: Option<String>,
     summary: Option<RouteSummary>,
     guide: Option<Vec<Guide>>,
+    /// fields not modeled above
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+impl RouteResult {
+    /// Names of response fields the structs do not model, e.g. "guide[].guideInfo.newField".
+    fn unknown_fields(&self) -> Vec<String> {
+        let mut fields = std::collections::BTreeSet::new();
+        fields.extend(self.extra.keys().cloned());
+        if let Some(summary) = &self.summary {
+            fields.extend(summary.extra.keys().map(|k| format!("summary.{}", k)));
+        }
+        for guide in self.guide.iter().flatten() {
+            fields.extend(guide.extra.keys().map(|k| format!("guide[].{}", k)));
+            if let Some(info) = &guide.guide_info {
+                fields.extend(
+                    info.extra
+                        .keys()
+                        .map(|k| format!("guide[].guideInfo.{}", k)),
+                );
+            }
+        }
+        fields.into_iter().collect()
+    }
+
+    /// Remove shape data from every guide.
+    fn strip_shape(&mut self) {
+        for info in self
+            .guide
+            .iter_mut()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_mut())
+        {
+            info.shape = None;
+            info.shape_info = None;
+            info.shape_points = None;
+            info.shape_index_first = None;
+            info.shape_index_last = None;
+        }
+    }
+
+    /// Guide entries in order, including start and goal, with cumulative distance and time.
+    fn maneuvers(&self) -> impl Iterator<Item = Maneuver<'_>> + '_ {
+        self.guide
+            .iter()
+            .flatten()
+            .scan((0.0, 0.0), |(total_distance, total_time), guide| {
+                let info = guide.guide_info.as_ref();
+                let distance = info.and_then(|i| i.distance).unwrap_or(0.0);
+                let travel_time = info.and_then(|i| i.travel_time).unwrap_or(0.0);
+                *total_distance += distance;
+                *total_time += travel_time;
+                Some(Maneuver {
+                    guide_type: guide.guide_type(),
+                    direction: info.and_then(|i| i.direction()),
+                    instruction: guide.instruction(),
+                    distance,
+                    travel_time,
+                    cumulative_distance: *total_distance,
+                    cumulative_time: *total_time,
+                })
+            })
+    }
+
+    /// Highway entrances/exits, service areas and ferry terminals along the route.
+    fn highlights(&self) -> Vec<Highlight> {
+        let mut highlights = vec![];
+        let mut distance = 0.0;
+        for info in self
+            .guide
+            .iter()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_ref())
+        {
+            if let Some(code) = info
+                .guide_detail
+                .as_ref()
+                .and_then(|d| d.code.as_ref()?.known())
+            {
+                let name = info
+                    .guide_detail
+                    .as_ref()
+                    .and_then(|d| d.name.clone())
+                    .or_else(|| info.facility_name())
+                    .unwrap_or_default();
+                highlights.push(Highlight {
+                    distance,
+                    code: *code,
+                    name,
+                });
+            }
+            distance += info.distance.unwrap_or(0.0);
+        }
+        highlights
+    }
+}
+
+impl RouteResult {
+    /// All shape points of the route in order as (lon, lat).
+    fn shape_points(&self) -> Vec<(f64, f64)> {
+        self.guide
+            .iter()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_ref())
+            .flat_map(|info| info.shape_points.iter().flatten())
+            .filter_map(|p| Some((p.lon?, p.lat?)))
+            .collect()
+    }
+
+    /// Total ascent/descent and the steepest grade from the shape point elevations. Elevations
+    /// are smoothed with a moving average over `window` points first; 1 uses them as is.
+    fn elevation(&self, window: usize) -> Option<Elevation> {
+        let points: Vec<((f64, f64), f64)> = self
+            .guide
+            .iter()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_ref())
+            .flat_map(|info| info.shape_points.iter().flatten())
+            .filter_map(|p| Some(((p.lon?, p.lat?), p.el? as f64)))
+            .collect();
+        if points.len() < 2 {
+            return None;
+        }
+        let half = window.max(1) / 2;
+        let smoothed: Vec<f64> = (0..points.len())
+            .map(|i| {
+                let range = i.saturating_sub(half)..(i + half + 1).min(points.len());
+                let len = range.len() as f64;
+                points[range].iter().map(|(_, el)| el).sum::<f64>() / len
+            })
+            .collect();
+
+        let mut elevation = Elevation::default();
+        for i in 1..points.len() {
+            let delta = smoothed[i] - smoothed[i - 1];
+            if delta > 0.0 {
+                elevation.ascent += delta;
+            } else {
+                elevation.descent -= delta;
+            }
+            let distance = distance_m(points[i - 1].0, points[i].0);
+            if distance > 0.0 {
+                elevation.max_grade = elevation.max_grade.max(delta.abs() / distance * 100.0);
+            }
+        }
+        Some(elevation)
+    }
+
+    /// Min and max lon/lat over all shape points and guide points.
+    fn bounding_box(&self) -> Option<(Point, Point)> {
+        let guide_points = self
+            .guide
+            .iter()
+            .flatten()
+            .flat_map(|g| g.guide_points.iter().flatten())
+            .filter_map(|p| Some((p.lon?, p.lat?)));
+        self.shape_points()
+            .into_iter()
+            .chain(guide_points)
+            .fold(None, |bbox, (lon, lat)| match bbox {
+                None => Some(((lon, lat), (lon, lat))),
+                Some(((min_lon, min_lat), (max_lon, max_lat))) => Some((
+                    (f64::min(min_lon, lon), f64::min(min_lat, lat)),
+                    (f64::max(max_lon, lon), f64::max(max_lat, lat)),
+                )),
+            })
+            .map(|((min_lon, min_lat), (max_lon, max_lat))| {
+                (
+                    Point {
+                        lon: Some(min_lon),
+                        lat: Some(min_lat),
+                    },
+                    Point {
+                        lon: Some(max_lon),
+                        lat: Some(max_lat),
+                    },
+                )
+            })
+    }
+
+    /// Drop shape points with Ramer-Douglas-Peucker, keeping start, goal and waypoints.
+    /// When points are dropped, the fields indexing into them (shape, shapeInfo and
+    /// shapeIndex*) are dropped as well since they no longer line up.
+    fn simplify(&mut self, tolerance_m: f64) -> Result<()> {
+        anyhow::ensure!(
+            tolerance_m.is_finite() && tolerance_m >= 0.0,
+            AppError::InvalidInput(format!(
+                "simplify tolerance must be a non-negative number: {}",
+                tolerance_m
+            ))
+        );
+        let mut points = vec![];
+        let mut anchors = vec![];
+        for guide in self.guide.iter().flatten() {
+            if matches!(
+                guide.guide_type(),
+                Some(GuideType::Start | GuideType::Goal | GuideType::Waypoint)
+            ) {
+                anchors.push(points.len());
+            }
+            points.extend(
+                guide
+                    .guide_info
+                    .iter()
+                    .flat_map(|info| info.shape_points.iter().flatten())
+                    .filter_map(|p| Some((p.lon?, p.lat?))),
+            );
+        }
+        if points.len() < 3 {
+            return Ok(());
+        }
+        let last = points.len() - 1;
+        anchors.push(0);
+        anchors.push(last);
+        let mut anchors: Vec<usize> = anchors.into_iter().map(|i| i.min(last)).collect();
+        anchors.sort_unstable();
+        anchors.dedup();
+
+        let mut keep = vec![false; points.len()];
+        for w in anchors.windows(2) {
+            douglas_peucker(&points, w[0], w[1], tolerance_m, &mut keep);
+        }
+        if keep.iter().all(|k| *k) {
+            return Ok(());
+        }
+
+        let mut index = 0;
+        for info in self
+            .guide
+            .iter_mut()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_mut())
+        {
+            if let Some(shape_points) = info.shape_points.as_mut() {
+                shape_points.retain(|p| {
+                    if p.lon.is_none() || p.lat.is_none() {
+                        return true;
+                    }
+                    index += 1;
+                    keep[index - 1]
+                });
+            }
+            info.shape = None;
+            info.shape_info = None;
+            info.shape_index_first = None;
+            info.shape_index_last = None;
+        }
+        Ok(())
+    }
+
+    /// Encode the shape points in Google's encoded polyline format.
+    fn encoded_polyline(&self, precision: u32) -> String {
+        let factor = 10f64.powi(precision as i32);
+        let mut encoded = String::new();
+        let (mut prev_lat, mut prev_lon) = (0i64, 0i64);
+        for (lon, lat) in self.shape_points() {
+            let lat = (lat * factor).round() as i64;
+            let lon = (lon * factor).round() as i64;
+            encode_polyline_value(lat - prev_lat, &mut encoded);
+            encode_polyline_value(lon - prev_lon, &mut encoded);
+            prev_lat = lat;
+            prev_lon = lon;
+        }
+        encoded
+    }
+
+    /// Render the route as KML with a LineString and placemarks for start, goal and waypoints.
+    fn to_kml(&self) -> String {
+        // KML orders coordinates as lon,lat,alt
+        let coordinates = self
+            .guide
+            .iter()
+            .flatten()
+            .filter_map(|g| g.guide_info.as_ref())
+            .flat_map(|info| info.shape_points.iter().flatten())
+            .filter_map(|p| Some(format!("{},{},{}", p.lon?, p.lat?, p.el.unwrap_or(0))))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut placemarks = vec![];
+        let mut waypoints = 0;
+        for guide in self.guide.iter().flatten() {
+            let label = match guide.guide_type() {
+                Some(GuideType::Start) => "Start".to_string(),
+                Some(GuideType::Goal) => "Goal".to_string(),
+                Some(GuideType::Waypoint) => {
+                    waypoints += 1;
+                    format!("Waypoint {}", waypoints)
+                }
+                _ => continue,
+            };
+            let info = guide.guide_info.as_ref();
+            let position = guide
+                .guide_points
+                .iter()
+                .flatten()
+                .find_map(|p| Some((p.lon?, p.lat?)))
+                .or_else(|| {
+                    info.and_then(|i| i.shape_points.as_ref())
+                        .and_then(|points| points.iter().find_map(|p| Some((p.lon?, p.lat?))))
+                });
+            let (lon, lat) = match position {
+                Some(position) => position,
+                None => continue,
+            };
+            let place = info
+                .and_then(|i| i.guide_crossing.as_ref())
+                .and_then(|c| c.name.clone())
+                .or_else(|| {
+                    info.and_then(|i| i.guide_road.as_ref())
+                        .and_then(|r| r.name.clone())
+                });
+            let name = match place {
+                Some(place) => format!("{}: {}", label, place),
+                None => label,
+            };
+            placemarks.push(format!(
+                "<Placemark><name>{}</name><Point><coordinates>{},{},0</coordinates></Point></Placemark>",
+                xml_escape(&name),
+                lon,
+                lat
+            ));
+        }
+
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<kml xmlns="http://www.opengis.net/kml/2.2">
+<Document>
+<name>mapfanrs route</name>
+<Placemark><name>Route</name><LineString><tessellate>1</tessellate><coordinates>{}</coordinates></LineString></Placemark>
+{}
+</Document>
+</kml>"#,
+            coordinates,
+            placemarks.join("\n")
+        )
+    }
+
+    /// Render the route as a self-contained Leaflet map.
+    fn to_html(&self) -> String {
+        let points = self.shape_points();
+        let latlngs = points
+            .iter()
+            .map(|(lon, lat)| format!("[{},{}]", lat, lon))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>mapfanrs route</title>
+<link rel="stylesheet" href="{LEAFLET_URL}/leaflet.css">
+<script src="{LEAFLET_URL}/leaflet.js"></script>
+<style>html, body, #map {{ height: 100%; margin: 0; }}</style>
+</head>
+<body>
+<div id="map"></div>
+<script>
+const latlngs = [{latlngs}];
+const map = L.map("map");
+L.tileLayer("https://{{s}}.tile.openstreetmap.org/{{z}}/{{x}}/{{y}}.png", {{
+  attribution: "&copy; OpenStreetMap contributors"
+}}).addTo(map);
+if (latlngs.length > 0) {{
+  const line = L.polyline(latlngs, {{ color: "blue" }}).addTo(map);
+  L.marker(latlngs[0]).bindPopup("Start").addTo(map);
+  L.marker(latlngs[latlngs.length - 1]).bindPopup("Goal").addTo(map);
+  map.fitBounds(line.getBounds());
+}} else {{
+  map.setView([35.681, 139.767], 10);
+}}
+</script>
+</body>
+</html>"#
+        )
+    }
+}
+
+/// Mark points between `first` and `last` to keep so the line stays within `tolerance_m`.
+fn douglas_peucker(
+    points: &[(f64, f64)],
+    first: usize,
+    last: usize,
+    tolerance_m: f64,
+    keep: &mut [bool],
+) {
+    keep[first] = true;
+    keep[last] = true;
+    let mut stack = vec![(first, last)];
+    while let Some((first, last)) = stack.pop() {
+        let farthest = (first + 1..last)
+            .map(|i| {
+                (
+                    i,
+                    segment_distance_m(points[i], points[first], points[last]),
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, d)) = farthest {
+            if d > tolerance_m {
+                keep[i] = true;
+                stack.push((first, i));
+                stack.push((i, last));
+            }
+        }
+    }
+}
+
+/// Distance(m) between `a` and `b` with the haversine formula.
+fn distance_m(a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let (lon1, lat1) = (a.0.to_radians(), a.1.to_radians());
+    let (lon2, lat2) = (b.0.to_radians(), b.1.to_radians());
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Distance(m) from `p` to the segment `a`-`b` on an equirectangular projection.
+fn segment_distance_m(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+    let cos_lat = a.1.to_radians().cos();
+    let project = |(lon, lat): (f64, f64)| {
+        (
+            lon.to_radians() * cos_lat * EARTH_RADIUS_M,
+            lat.to_radians() * EARTH_RADIUS_M,
+        )
+    };
+    let (px, py) = project(p);
+    let (ax, ay) = project(a);
+    let (bx, by) = project(b);
+    let (dx, dy) = (bx - ax, by - ay);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * dx + (py - ay) * dy) / len2).clamp(0.0, 1.0)
+    };
+    ((px - ax - t * dx).powi(2) + (py - ay - t * dy).powi(2)).sqrt()
+}
+
+fn encode_polyline_value(value: i64, encoded: &mut String) {
+    let mut v = if value < 0 { !(value << 1) } else { value << 1 };
+    while v >= 0x20 {
+        encoded.push((((v & 0x1f) | 0x20) as u8 + 63) as char);
+        v >>= 5;
+    }
+    encoded.push((v as u8 + 63) as char);
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const LEAFLET_URL: &str = "https://unpkg.com/leaflet@1.9.4/dist";
+
+/// One guide entry with distances accumulated from the start.
+#[derive(Debug)]
+struct Maneuver<'a> {
+    guide_type: Option<&'a GuideType>,
+    direction: Option<&'a GuideDirection>,
+    instruction: String,
+    /// distance of this segment(m)
+    distance: f64,
+    /// travel time of this segment(s)
+    travel_time: f64,
+    /// distance from the start to the end of this segment(m)
+    cumulative_distance: f64,
+    /// travel time from the start to the end of this segment(s)
+    cumulative_time: f64,
+}
+
+impl Guide {
+    /// Instruction text like "Turn right at 渋谷駅前 onto 国道246号".
+    fn instruction(&self) -> String {
+        let info = self.guide_info.as_ref();
+        let mut text = match self.guide_type() {
+            Some(GuideType::Start) => "Start".to_string(),
+            Some(GuideType::Goal) => "Arrive at goal".to_string(),
+            Some(GuideType::Waypoint) => "Arrive at waypoint".to_string(),
+            _ => info
+                .and_then(|i| i.direction())
+                .map(|d| d.instruction())
+                .unwrap_or("Continue")
+                .to_string(),
+        };
+        if let Some(crossing) = info
+            .and_then(|i| i.guide_crossing.as_ref())
+            .and_then(|c| c.name.as_ref())
+        {
+            text += &format!(" at {}", crossing);
+        }
+        if let Some(road) = info
+            .and_then(|i| i.guide_road.as_ref())
+            .and_then(|r| r.name.as_ref())
+        {
+            text += &format!(" onto {}", road);
+        }
+        text
+    }
+}
+
+#[derive(Debug)]
+struct Highlight {
+    /// cumulative distance from the start(m)
+    distance: f64,
+    code: GuideDetailCode,
+    name: String,
+}
+
+impl Highlight {
+    fn to_text(&self, units: Units) -> String {
+        let kind = match self.code {
+            GuideDetailCode::HighwayEntrance => "entrance",
+            GuideDetailCode::HighwayExit => "exit",
+            GuideDetailCode::HighwayService => "service",
+            GuideDetailCode::FerryTerminal => "ferry",
+        };
+        format!(
+            "{:>11}\t{}\t{}",
+            units.distance(self.distance),
+            kind,
+            self.name
+        )
+    }
+}
+
+/// Climb and descent along the route.
+#[derive(Debug, Default, PartialEq)]
+struct Elevation {
+    /// sum of the positive elevation deltas(m)
+    ascent: f64,
+    /// sum of the negative elevation deltas(m), as a positive number
+    descent: f64,
+    /// steepest grade between two consecutive shape points(%), uphill or downhill
+    max_grade: f64,
+}
+
+impl Elevation {
+    fn to_text(&self, units: Units) -> String {
+        [
+            format!("Ascent:      {}", units.height(self.ascent)),
+            format!("Descent:     {}", units.height(self.descent)),
+            format!("Max grade:   {:.1} %", self.max_grade),
+        ]
+        .join("\n")
+    }
+}
+
+/// Units used to present distances and speeds. Values are always kept in meters internally.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Units {
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    const METERS_PER_MILE: f64 = 1609.344;
+    const METERS_PER_FOOT: f64 = 0.3048;
+
+    /// Format a distance given in meters.
+    fn distance(&self, meters: f64) -> String {
+        match self {
+            Units::Metric => format!("{:.1} km", meters / 1000.0),
+            Units::Imperial => format!("{:.1} mi", meters / Self::METERS_PER_MILE),
+        }
+    }
+
+    /// Format a height or elevation given in meters.
+    fn height(&self, meters: f64) -> String {
+        match self {
+            Units::Metric => format!("{:.0} m", meters),
+            Units::Imperial => format!("{:.0} ft", meters / Self::METERS_PER_FOOT),
+        }
+    }
+
+    /// Format a speed given in km/h.
+    fn speed(&self, kmh: f64) -> String {
+        match self {
+            Units::Metric => format!("{:.1} km/h", kmh),
+            Units::Imperial => format!("{:.1} mph", kmh * 1000.0 / Self::METERS_PER_MILE),
+        }
+    }
+}
+
+/// Numeric code in a response. Codes not covered by `T` are kept as `Unknown`
+/// so that a new or reserved value does not fail the whole response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Code<T> {
+    Known(T),
+    Unknown(u16),
+}
+
+impl<T> Code<T> {
+    fn known(&self) -> Option<&T> {
+        match self {
+            Code::Known(v) => Some(v),
+            Code::Unknown(_) => None,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Code<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Code::Known(v) => v.serialize(serializer),
+            Code::Unknown(code) => serializer.serialize_u16(*code),
+        }
+    }
+}
+
+impl<'de, T: serde::de::DeserializeOwned> Deserialize<'de> for Code<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::value::{Error, U16Deserializer};
+        let code = u16::deserialize(deserializer)?;
+        Ok(match T::deserialize(U16Deserializer::<Error>::new(code)) {
+            Ok(v) => Code::Known(v),
+            Err(_) => Code::Unknown(code),
+        })
+    }
+}
+
+impl Guide {
+    fn guide_type(&self) -> Option<&GuideType> {
+        self.type_.as_ref()?.known()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Guide {
     #[serde(rename = "type")]
-    type_: Option<GuideType>,
+    type_: Option<Code<GuideType>>,
     #[serde(rename = "guidePoints")]
     guide_points: Option<Vec<Point>>,
     #[serde(rename = "guideInfo")]
     guide_info: Option<GuideInfo>,
+    /// fields not modeled above
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct GuideInfo {
     #[serde(rename = "guideDirection")]
-    guide_direction: Option<GuideDirection>,
+    guide_direction: Option<Code<GuideDirection>>,
 
     #[serde(rename = "roadType")]
     road_type: Option<u16>,
@@ -429,7 +1920,7 @@ struct GuideInfo {
     shape_index_last: Option<ShapeIndex>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    shape: Option<Vec<ShapeType>>,
+    shape: Option<Vec<Code<ShapeType>>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "shapeInfo")]
@@ -441,6 +1932,44 @@ struct GuideInfo {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     order: Option<Vec<u32>>,
+    /// fields not modeled above
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+impl GuideInfo {
+    fn road_type_enum(&self) -> Option<RoadType> {
+        self.road_type.and_then(RoadType::from_code)
+    }
+
+    fn direction(&self) -> Option<&GuideDirection> {
+        self.guide_direction.as_ref()?.known()
+    }
+
+    /// Known shape types. Unknown codes are skipped.
+    fn shape_types(&self) -> Vec<&ShapeType> {
+        self.shape
+            .iter()
+            .flatten()
+            .filter_map(Code::known)
+            .collect()
+    }
+
+    /// Name of the first SA/PA on this guide.
+    fn facility_name(&self) -> Option<String> {
+        self.guide_highway
+            .as_ref()?
+            .facilities
+            .iter()
+            .flatten()
+            .find(|f| {
+                matches!(
+                    f.type_.as_ref().and_then(Code::known),
+                    Some(FacilityType::Sa | FacilityType::Pa)
+                )
+            })
+            .and_then(|f| f.name.clone())
+    }
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
@@ -462,6 +1991,22 @@ enum GuideDirection {
     Left30 = 13,
 }
 
+impl GuideDirection {
+    fn instruction(&self) -> &str {
+        match self {
+            GuideDirection::Unknown | GuideDirection::Along => "Continue",
+            GuideDirection::Straight => "Go straight",
+            GuideDirection::Right30 | GuideDirection::Right45 => "Bear right",
+            GuideDirection::Right => "Turn right",
+            GuideDirection::Right135 | GuideDirection::Right150 => "Sharp right",
+            GuideDirection::Uturn => "Make a U-turn",
+            GuideDirection::Left150 | GuideDirection::Left135 => "Sharp left",
+            GuideDirection::Left => "Turn left",
+            GuideDirection::Left45 | GuideDirection::Left30 => "Bear left",
+        }
+    }
+}
+
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
 #[repr(u8)]
 enum RoadType {
@@ -491,10 +2036,18 @@ enum RoadType {
     //OtherNormal = 300 ~ 399
 }
 
+impl RoadType {
+    /// Map a numeric road type code to the enum. Reserved and range-based codes return `None`.
+    fn from_code(code: u16) -> Option<Self> {
+        // Deserialize_repr accepts exactly the discriminants declared above
+        serde_json::from_value(code.into()).ok()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ShapePoint {
-    lon: Option<f32>,
-    lat: Option<f32>,
+    lon: Option<f64>,
+    lat: Option<f64>,
     el: Option<u32>,
 }
 
@@ -540,11 +2093,11 @@ struct ShapeIndex {
 #[derive(Serialize, Deserialize, Debug)]
 struct GuideTollEtc {
     #[serde(rename = "tollGateCode")]
-    toll_gate_code: Option<TollGateCode>,
+    toll_gate_code: Option<Code<TollGateCode>>,
     toll: Option<i64>,
     name: Option<String>,
     #[serde(rename = "etcCode")]
-    etc_code: Option<EtcCode>,
+    etc_code: Option<Code<EtcCode>>,
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
@@ -558,7 +2111,7 @@ enum EtcCode {
 #[derive(Serialize, Deserialize, Debug)]
 struct GuideToll {
     #[serde(rename = "tollGateCode")]
-    toll_gate_code: Option<TollGateCode>,
+    toll_gate_code: Option<Code<TollGateCode>>,
     toll: Option<i64>,
     name: Option<String>,
 }
@@ -595,7 +2148,7 @@ struct GuideHighway {
 #[derive(Serialize, Deserialize, Debug)]
 struct Facility {
     #[serde(rename = "type")]
-    type_: Option<FacilityType>,
+    type_: Option<Code<FacilityType>>,
     name: Option<String>,
     // bitwize operation is necessary
     // bit
@@ -635,11 +2188,11 @@ enum FacilityType {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct GuideDetail {
-    code: Option<GuideDetailCode>,
+    code: Option<Code<GuideDetailCode>>,
     name: Option<String>,
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Copy, Debug)]
 #[repr(u8)]
 enum GuideDetailCode {
     HighwayEntrance = 32,
@@ -650,8 +2203,8 @@ enum GuideDetailCode {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Point {
-    lon: Option<f32>,
-    lat: Option<f32>,
+    lon: Option<f64>,
+    lat: Option<f64>,
 }
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Debug)]
@@ -663,11 +2216,13 @@ enum GuideType {
     Waypoint = 3,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct RouteSummary {
+    /// meters
     #[serde(rename = "totalDistance")]
     total_distance: Option<f64>,
 
+    /// seconds
     #[serde(rename = "totalTravelTime")]
     total_travel_time: Option<f64>,
 
@@ -682,14 +2237,146 @@ struct RouteSummary {
 
     #[serde(rename = "sectionTime")]
     section_time: Option<Vec<f64>>,
+    /// fields not modeled above
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl RouteSummary {
+    /// Total distance in kilometers.
+    fn total_distance_km(&self) -> Option<f64> {
+        self.total_distance.map(|d| d / 1000.0)
+    }
+
+    /// Total travel time in minutes.
+    fn total_travel_time_minutes(&self) -> Option<f64> {
+        self.total_travel_time.map(|t| t / 60.0)
+    }
+
+    /// Total travel time as (hours, minutes, seconds).
+    fn total_travel_time_hms(&self) -> Option<(u32, u32, u32)> {
+        self.total_travel_time.map(|t| {
+            let secs = t.round() as u32;
+            (secs / 3600, secs % 3600 / 60, secs % 60)
+        })
+    }
+}
+
+impl RouteSummary {
+    /// Human readable summary.
+    fn to_text(&self, units: Units) -> String {
+        let mut lines = vec![];
+        if let Some(distance) = self.total_distance {
+            lines.push(format!("Distance:    {}", units.distance(distance)));
+        }
+        if let Some(time) = self.total_travel_time {
+            lines.push(format!("Travel time: {}", format_duration(time)));
+        }
+        if let (Some(distance), Some(time)) = (self.total_distance, self.total_travel_time) {
+            if time > 0.0 {
+                lines.push(format!(
+                    "Avg. speed:  {}",
+                    units.speed(distance / 1000.0 / (time / 3600.0))
+                ));
+            }
+        }
+        if let Some(toll) = self.toll() {
+            lines.push(format!("Toll:        {} yen", toll));
+        }
+        if let Some(toll) = self.toll_etc() {
+            lines.push(format!("Toll (ETC):  {} yen", toll));
+        }
+        if let Some(departure) = &self.departure_time {
+            match departure.to_naive() {
+                Some(departure) => {
+                    lines.push(format!(
+                        "Departure:   {}",
+                        departure.format("%Y-%m-%d %H:%M:%S")
+                    ));
+                    if let Some(time) = self.total_travel_time {
+                        let arrival = departure + chrono::Duration::seconds(time.round() as i64);
+                        lines.push(format!(
+                            "Arrival:     {}",
+                            arrival.format("%Y-%m-%d %H:%M:%S")
+                        ));
+                    }
+                }
+                None => lines.push(format!(
+                    "Departure:   {} {}",
+                    departure.date.as_deref().unwrap_or(""),
+                    departure.time.as_deref().unwrap_or("")
+                )),
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+impl RouteSummary {
+    /// Travel time of each leg. `stops` are the start, via points and goal bounding the legs.
+    fn sections_text(&self, stops: &[String]) -> String {
+        let legs = match &self.section_time {
+            Some(times) if !times.is_empty() => times.clone(),
+            _ => self.total_travel_time.into_iter().collect(),
+        };
+        let stop = |i: usize| stops.get(i).map(|s| s.as_str()).unwrap_or("?");
+        legs.iter()
+            .enumerate()
+            .map(|(i, time)| {
+                format!(
+                    "{}\t{} -> {}\t{:.1} min",
+                    i + 1,
+                    stop(i),
+                    stop(i + 1),
+                    time / 60.0
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl RouteSummary {
+    fn toll(&self) -> Option<f64> {
+        self.total_toll.as_ref()?.toll
+    }
+
+    fn toll_etc(&self) -> Option<f64> {
+        self.total_toll_etc.as_ref()?.toll
+    }
+
+    /// Cash and ETC toll with the savings from ETC. Missing tolls are reported as zero.
+    fn toll_compare_text(&self) -> String {
+        let cash = self.toll().unwrap_or(0.0);
+        let etc = self.toll_etc().unwrap_or(cash);
+        format!(
+            "Toll (cash):  {} yen\nToll (ETC):   {} yen\nETC savings:  {} yen",
+            cash,
+            etc,
+            cash - etc
+        )
+    }
+}
+
+/// Format seconds as "H:MM:SS", with a leading '-' for negative values.
+fn format_duration(seconds: f64) -> String {
+    let sign = if seconds < 0.0 { "-" } else { "" };
+    let secs = seconds.abs().round() as u32;
+    format!(
+        "{}{}:{:02}:{:02}",
+        sign,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct Toll {
     toll: Option<f64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct DateTime {
     /// yyyyMMdd
     date: Option<String>,
@@ -698,75 +2385,1168 @@ struct DateTime {
     time: Option<String>,
 }
 
-async fn handle_route(route_args: RouteArgs) -> Result<()> {
-    const BASE_URL: &str = "https://mapfanapi-route.p.rapidapi.com/calcroute";
-    let header = RequestHeader::new();
-    let origin: Vec<f32> = route_args
-        .from
-        .split(",")
-        .map(|v| v.parse::<f32>().expect("input must be a float number"))
-        .collect();
-    let dest: Vec<f32> = route_args
-        .to
-        .split(",")
-        .map(|v| v.parse::<f32>().expect("input must be a float number"))
-        .collect();
-    anyhow::ensure!(
-        origin.len() == 2 && dest.len() == 2,
-        "invalid from/to parameter, it must be 'lon,lat' format. from: {}, to: {}",
-        route_args.from,
-        route_args.to
+impl DateTime {
+    /// Combine `date` and `time`. `None` if either is missing or malformed.
+    fn to_naive(&self) -> Option<chrono::NaiveDateTime> {
+        let date = chrono::NaiveDate::parse_from_str(self.date.as_ref()?, "%Y%m%d").ok()?;
+        let time = chrono::NaiveTime::parse_from_str(self.time.as_ref()?, "%H%M%S").ok()?;
+        Some(date.and_time(time))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct GeocodeResult {
+    results: Option<Vec<GeocodeItem>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct GeocodeItem {
+    name: Option<String>,
+    lon: Option<f64>,
+    lat: Option<f64>,
+}
+
+/// Client of the MapFan search API. Resolved names are cached for the lifetime of the client.
+struct GeocodeClient {
+    client: ApiClient,
+    base_url: String,
+    header: RequestHeader,
+    cache: HashMap<String, Position>,
+}
+
+const GEOCODE_HOST: &str = "mapfanapi-search.p.rapidapi.com";
+const GEOCODE_BASE_URL: &str = "https://mapfanapi-search.p.rapidapi.com/addr";
+
+impl GeocodeClient {
+    fn new(client: ApiClient, base_url: &str, header: RequestHeader) -> Self {
+        Self {
+            client,
+            base_url: base_url.to_string(),
+            header,
+            cache: HashMap::new(),
+        }
+    }
+
+    async fn search(&self, query: &str) -> Result<Vec<GeocodeItem>> {
+        let url = reqwest::Url::parse_with_params(&self.base_url, &[("addr", query)])?;
+        log::debug!("GET {}, header: {:?}", url, self.header);
+        let started = std::time::Instant::now();
+        self.client.stats.record_request();
+        let res = self
+            .client
+            .http
+            .get(url)
+            .header("X-RapidAPI-Key", &self.header.api_key)
+            .header("X-RapidAPI-Host", &self.header.api_host)
+            .send()
+            .await?;
+        log::debug!("status: {}, elapsed: {:?}", res.status(), started.elapsed());
+        let res = check_status(res).await?;
+        let obj: GeocodeResult =
+            serde_json::from_str(&res.text().await?).map_err(|e| AppError::Parse(e.to_string()))?;
+        Ok(obj.results.unwrap_or_default())
+    }
+
+    /// Coordinates of the best match for `query`.
+    async fn resolve(&mut self, query: &str) -> Result<Position> {
+        if let Some(position) = self.cache.get(query) {
+            self.client.stats.record_cache_hit();
+            return Ok(*position);
+        }
+        let position = self
+            .search(query)
+            .await?
+            .iter()
+            .find_map(|item| {
+                Some(Position {
+                    longitude: item.lon?,
+                    latitude: item.lat?,
+                })
+            })
+            .ok_or_else(|| AppError::InvalidInput(format!("no geocode result for: {}", query)))?;
+        self.cache.insert(query.to_string(), position);
+        Ok(position)
+    }
+
+    /// Parse "lon,lat" or resolve "name:<place name>".
+    async fn position(&mut self, s: &str) -> Result<Position> {
+        match s.strip_prefix("name:") {
+            Some(name) => self.resolve(name).await,
+            None => s.parse(),
+        }
+    }
+}
+
+async fn handle_geocode(geocode_args: GeocodeArgs, client: &ApiClient) -> Result<()> {
+    let geocoder = GeocodeClient::new(
+        client.clone(),
+        GEOCODE_BASE_URL,
+        RequestHeader::new_with_host(GEOCODE_HOST)?,
     );
+    for item in geocoder.search(&geocode_args.query).await? {
+        if let (Some(lon), Some(lat)) = (item.lon, item.lat) {
+            println!("{},{}\t{}", lon, lat, item.name.unwrap_or_default());
+        }
+    }
+    Ok(())
+}
 
-    let start = Position {
-        longitude: origin[0],
-        latitude: origin[1],
+/// Render a parsed route according to the output options.
+fn render_route(obj: &RouteResult, route_args: &RouteArgs) -> Result<String> {
+    let units = if route_args.imperial {
+        Units::Imperial
+    } else {
+        Units::Metric
     };
-    let destination = Position {
-        longitude: dest[0],
-        latitude: dest[1],
+    let out_str = match route_args.format {
+        Format::Json if route_args.highlights => obj
+            .highlights()
+            .iter()
+            .map(|h| h.to_text(units))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Format::Json => route_json(obj, route_args)?,
+        Format::Xml => unreachable!("XML responses are not parsed"),
+        Format::Html => obj.to_html(),
+        Format::Summary => obj
+            .summary
+            .as_ref()
+            .map(|s| s.to_text(units))
+            .into_iter()
+            .chain(
+                obj.elevation(route_args.grade_window)
+                    .map(|e| e.to_text(units)),
+            )
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Format::Polyline => obj.encoded_polyline(route_args.polyline_precision),
+        Format::Kml => obj.to_kml(),
+        Format::TollCompare => obj
+            .summary
+            .as_ref()
+            .map(|s| s.toll_compare_text())
+            .unwrap_or_else(|| RouteSummary::default().toll_compare_text()),
+        Format::Sections => {
+            let mut stops = vec![route_args.from.clone().unwrap_or_default()];
+            if let Some(via) = &route_args.via {
+                stops.extend(
+                    via.split('|')
+                        .map(|v| v.splitn(3, ',').take(2).collect::<Vec<_>>().join(",")),
+                );
+            }
+            stops.push(route_args.to.clone().unwrap_or_default());
+            obj.summary
+                .as_ref()
+                .map(|s| s.sections_text(&stops))
+                .unwrap_or_default()
+        }
     };
-    let mut params = CalcRouteRequestParam::new(start, destination);
-    params.vehicle_type(VehicleType::BigCargo);
-    if let Some(date) = route_args.date {
-        params.date(date);
+    Ok(out_str)
+}
+
+/// The route as JSON, limited to "--fields" if given.
+fn route_json(obj: &RouteResult, route_args: &RouteArgs) -> Result<String> {
+    let mut json = serde_json::to_value(obj)?;
+    if let Some(fields) = &route_args.fields {
+        json = project_fields(&json, fields)?;
+    }
+    Ok(if route_args.pretty {
+        serde_json::to_string_pretty(&json)?
+    } else {
+        serde_json::to_string(&json)?
+    })
+}
+
+/// Keep only the comma separated dotted `fields` of `json`. A path through an array applies
+/// to each of its elements.
+fn project_fields(json: &serde_json::Value, fields: &str) -> Result<serde_json::Value> {
+    let mut projected = serde_json::Value::Object(Default::default());
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let path: Vec<&str> = field.split('.').collect();
+        match project_path(json, &path) {
+            Some(value) => merge_json(&mut projected, value),
+            None => {
+                let keys = json
+                    .as_object()
+                    .map(|o| o.keys().cloned().collect::<Vec<_>>().join(", "))
+                    .unwrap_or_default();
+                anyhow::bail!(AppError::InvalidInput(format!(
+                    "unknown field: {}, valid top-level keys are: {}",
+                    field, keys
+                )));
+            }
+        }
     }
-    if let Some(via) = route_args.via {
-        params.via(via);
+    Ok(projected)
+}
+
+fn project_path(json: &serde_json::Value, path: &[&str]) -> Option<serde_json::Value> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Some(json.clone()),
+    };
+    match json {
+        serde_json::Value::Object(o) => {
+            let value = project_path(o.get(*key)?, rest)?;
+            Some(serde_json::json!({ *key: value }))
+        }
+        serde_json::Value::Array(a) => {
+            let values: Vec<_> = a.iter().map(|v| project_path(v, path)).collect();
+            if values.iter().all(Option::is_none) && !a.is_empty() {
+                return None;
+            }
+            Some(values.into_iter().map(Option::unwrap_or_default).collect())
+        }
+        // nothing to project below a missing object
+        serde_json::Value::Null => Some(serde_json::Value::Null),
+        _ => None,
     }
+}
 
-    let url = reqwest::Url::parse_with_params(BASE_URL, params.to_params())?;
-    let client = reqwest::Client::new();
-    let req = client
-        .get(url)
-        .header("X-RapidAPI-Key", header.api_key)
-        .header("X-RapidAPI-Host", header.api_host);
-    let res = req.send().await?;
-    anyhow::ensure!(res.status() == StatusCode::OK, "{:?}", res);
+/// Merge `other` into `json`, combining objects by key and arrays by index.
+fn merge_json(json: &mut serde_json::Value, other: serde_json::Value) {
+    match (json, other) {
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            for (k, v) in b {
+                match a.get_mut(&k) {
+                    Some(existing) => merge_json(existing, v),
+                    None => {
+                        a.insert(k, v);
+                    }
+                }
+            }
+        }
+        (serde_json::Value::Array(a), serde_json::Value::Array(b)) => {
+            for (existing, v) in a.iter_mut().zip(b) {
+                merge_json(existing, v);
+            }
+        }
+        (json, other) => *json = other,
+    }
+}
 
-    let output = res.text().await?;
-    let obj: RouteResult = serde_json::from_str(&output).unwrap();
-    let json_str = serde_json::to_string(&obj).unwrap();
+/// Number of API requests sent and of lookups answered from a cache, reported with "--verbose".
+#[derive(Debug, Default)]
+struct RequestStats {
+    requests: AtomicUsize,
+    cache_hits: AtomicUsize,
+}
 
-    if let Some(file) = route_args.file {
-        fs::write(file, &json_str)?;
-    } else {
-        println!("{}", json_str);
+impl RequestStats {
+    fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
     }
 
-    Ok(())
+    fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn to_text(&self) -> String {
+        format!(
+            "{} API requests made ({} cache hits)",
+            self.requests.load(Ordering::Relaxed),
+            self.cache_hits.load(Ordering::Relaxed)
+        )
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// HTTP client for the API requests. Clones share the same `RequestStats`.
+#[derive(Debug, Clone)]
+struct ApiClient {
+    http: reqwest::Client,
+    stats: std::sync::Arc<RequestStats>,
+}
 
-    #[test]
-    fn onoff_should_be_number() {
-        let params = CalcRouteRequestParam {
-            tollroad: Some(OnOff::On),
-            ..Default::default()
-        };
+impl ApiClient {
+    fn new(http: reqwest::Client) -> Self {
+        Self {
+            http,
+            stats: Default::default(),
+        }
+    }
+}
+
+#[cfg(not(any(feature = "rustls-tls", feature = "native-tls")))]
+compile_error!("enable one of the \"rustls-tls\" or \"native-tls\" features for HTTPS");
+
+/// HTTP client using the TLS backend selected by cargo features. "native-tls" wins when
+/// both are enabled, so `--features native-tls` works without `--no-default-features`.
+fn http_client() -> reqwest::Client {
+    let builder = reqwest::Client::builder();
+    #[cfg(feature = "native-tls")]
+    let builder = builder.use_native_tls();
+    #[cfg(all(feature = "rustls-tls", not(feature = "native-tls")))]
+    let builder = builder.use_rustls_tls();
+    builder.build().expect("failed to build HTTP client")
+}
+
+const ROUTE_BASE_URL: &str = "https://mapfanapi-route.p.rapidapi.com/calcroute";
+
+fn route_url(base_url: &str, params: &CalcRouteRequestParam) -> Result<reqwest::Url> {
+    params.validate()?;
+    Ok(reqwest::Url::parse_with_params(
+        base_url,
+        params.to_params(),
+    )?)
+}
+
+/// Request a route and return the response body as is.
+async fn fetch_route_text(
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    params: &CalcRouteRequestParam,
+) -> Result<String> {
+    let url = route_url(base_url, params)?;
+    log::debug!("GET {}, header: {:?}", url, header);
+    let req = client
+        .http
+        .get(url)
+        .header("X-RapidAPI-Key", &header.api_key)
+        .header("X-RapidAPI-Host", &header.api_host);
+    let started = std::time::Instant::now();
+    client.stats.record_request();
+    let res = req.send().await?;
+    log::debug!("status: {}, elapsed: {:?}", res.status(), started.elapsed());
+    let res = check_status(res).await?;
+    Ok(res.text().await?)
+}
+
+/// Request a route and parse the JSON response.
+async fn fetch_route(
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    params: &CalcRouteRequestParam,
+) -> Result<RouteResult> {
+    parse_route(&fetch_route_text(client, base_url, header, params).await?)
+}
+
+fn parse_route(body: &str) -> Result<RouteResult> {
+    let route: RouteResult =
+        serde_json::from_str(body).map_err(|e| AppError::Parse(e.to_string()))?;
+    let unknown = route.unknown_fields();
+    if !unknown.is_empty() {
+        log::warn!("response has unknown fields: {}", unknown.join(", "));
+    }
+    match &route.status {
+        Some(status) if !status.eq_ignore_ascii_case("OK") => {
+            Err(AppError::RouteStatus(status.clone()).into())
+        }
+        _ => Ok(route),
+    }
+}
+
+/// Load a route saved from a previous response, e.g. with "--output".
+fn replay_route(path: &str) -> Result<RouteResult> {
+    parse_route(&fs::read_to_string(path)?)
+}
+
+/// Fetch routes for all `params` with at most `concurrency` requests in flight.
+/// Results are returned in the order of `params`.
+async fn fetch_routes(
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    params: Vec<CalcRouteRequestParam>,
+    concurrency: usize,
+) -> Vec<Result<RouteResult>> {
+    fetch_routes_with_progress(client, base_url, header, params, concurrency, |_, _, _| {}).await
+}
+
+/// Same as `fetch_routes`, calling `on_progress(completed, total, result)` as each route
+/// finishes. Routes complete in any order.
+async fn fetch_routes_with_progress<F>(
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    params: Vec<CalcRouteRequestParam>,
+    concurrency: usize,
+    mut on_progress: F,
+) -> Vec<Result<RouteResult>>
+where
+    F: FnMut(usize, usize, &Result<RouteResult>),
+{
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    let total = params.len();
+    for (i, p) in params.into_iter().enumerate() {
+        let (client, base_url, header) = (client.clone(), base_url.to_string(), header.clone());
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await;
+            (i, fetch_route(&client, &base_url, &header, &p).await)
+        });
+    }
+    let mut results: Vec<Option<Result<RouteResult>>> = (0..total).map(|_| None).collect();
+    let mut completed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        completed += 1;
+        match joined {
+            Ok((i, result)) => {
+                on_progress(completed, total, &result);
+                results[i] = Some(result);
+            }
+            Err(e) => {
+                log::error!("route task failed: {}", e);
+                on_progress(completed, total, &Err(e.into()));
+            }
+        }
+    }
+    results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(anyhow::anyhow!("route task did not complete"))))
+        .collect()
+}
+
+/// Parse "lon,lat;lon,lat;..." or the content of a file with one "lon,lat" per line.
+fn parse_points(s: &str) -> Result<Vec<Position>> {
+    let content = if std::path::Path::new(s).is_file() {
+        fs::read_to_string(s)?
+    } else {
+        s.to_string()
+    };
+    content
+        .split(|c: char| c == ';' || c.is_whitespace())
+        .filter(|p| !p.is_empty())
+        .map(|p| p.parse())
+        .collect()
+}
+
+async fn handle_matrix(matrix_args: MatrixArgs, client: &ApiClient) -> Result<()> {
+    let points = parse_points(&matrix_args.points)?;
+    let header = RequestHeader::new()?;
+    let results = fetch_matrix(
+        client,
+        ROUTE_BASE_URL,
+        &header,
+        &points,
+        matrix_args.concurrency,
+    )
+    .await;
+    write_output(matrix_args.file.as_deref(), matrix_text(&points, &results))
+}
+
+/// Fetch the routes of `matrix_pairs` for `points`.
+async fn fetch_matrix(
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    points: &[Position],
+    concurrency: usize,
+) -> Vec<Result<RouteResult>> {
+    let params = matrix_pairs(points.len())
+        .into_iter()
+        .map(|(i, j)| CalcRouteRequestParam::new(points[i], points[j]))
+        .collect();
+    fetch_routes_with_progress(
+        client,
+        base_url,
+        header,
+        params,
+        concurrency,
+        |completed, total, _| log::info!("{}/{} routes done", completed, total),
+    )
+    .await
+}
+
+/// Ordered pairs of `n` points to request routes for. Self-pairs are left out.
+fn matrix_pairs(n: usize) -> Vec<(usize, usize)> {
+    (0..n)
+        .flat_map(|i| (0..n).map(move |j| (i, j)))
+        .filter(|(i, j)| i != j)
+        .collect()
+}
+
+/// CSV tables of travel time(min) and distance(km) between all `points`. `results` are the
+/// routes of `matrix_pairs` in order; failed routes are left empty.
+fn matrix_text(points: &[Position], results: &[Result<RouteResult>]) -> String {
+    let n = points.len();
+    let mut times = vec![vec![Some(0.0); n]; n];
+    let mut distances = vec![vec![Some(0.0); n]; n];
+    for ((i, j), result) in matrix_pairs(n).into_iter().zip(results) {
+        let summary = match result {
+            Ok(route) => route.summary.as_ref(),
+            Err(e) => {
+                log::warn!("route {} -> {} failed: {}", i, j, e);
+                None
+            }
+        };
+        times[i][j] = summary.and_then(|s| s.total_travel_time_minutes());
+        distances[i][j] = summary.and_then(|s| s.total_distance_km());
+    }
+
+    let labels: Vec<String> = points
+        .iter()
+        .map(|p| format!("\"{},{}\"", p.longitude, p.latitude))
+        .collect();
+    let table = |name: &str, values: &[Vec<Option<f64>>]| {
+        let mut lines = vec![format!("{},{}", name, labels.join(","))];
+        for (label, row) in labels.iter().zip(values) {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|v| v.map(|v| format!("{:.1}", v)).unwrap_or_default())
+                .collect();
+            lines.push(format!("{},{}", label, cells.join(",")));
+        }
+        lines.join("\n")
+    };
+    format!(
+        "{}\n\n{}",
+        table("travel_time_min", &times),
+        table("distance_km", &distances)
+    )
+}
+
+/// When only one of the cash/ETC tolls is in the response, fetch the route again with
+/// `etc` switched to get the other one.
+async fn fill_missing_toll(
+    obj: &mut RouteResult,
+    client: &ApiClient,
+    base_url: &str,
+    header: &RequestHeader,
+    params: &CalcRouteRequestParam,
+) -> Result<()> {
+    let summary = match obj.summary.as_mut() {
+        Some(summary) => summary,
+        None => return Ok(()),
+    };
+    let etc = match (summary.toll(), summary.toll_etc()) {
+        (Some(_), None) => OnOff::On,
+        (None, Some(_)) => OnOff::Off,
+        _ => return Ok(()),
+    };
+    let mut params = params.clone();
+    params.etc(etc);
+    let other = fetch_route(client, base_url, header, &params)
+        .await?
+        .summary
+        .unwrap_or_default();
+    match etc {
+        OnOff::On => summary.total_toll_etc = other.total_toll_etc.or(other.total_toll),
+        OnOff::Off => summary.total_toll = other.total_toll,
+    }
+    Ok(())
+}
+
+/// Side by side table of two route summaries with the delta of B against A.
+fn compare_text(a: &RouteSummary, b: &RouteSummary, units: Units) -> String {
+    let row = |name: &str, a: Option<f64>, b: Option<f64>, fmt: &dyn Fn(f64) -> String| {
+        let delta = match (a, b) {
+            (Some(a), Some(b)) => {
+                let sign = if b - a > 0.0 { "+" } else { "" };
+                format!("{}{}", sign, fmt(b - a))
+            }
+            _ => "-".to_string(),
+        };
+        format!(
+            "{:<12}{:>14}{:>14}{:>14}",
+            name,
+            a.map(fmt).unwrap_or_else(|| "-".to_string()),
+            b.map(fmt).unwrap_or_else(|| "-".to_string()),
+            delta
+        )
+    };
+    let distance = |m: f64| units.distance(m);
+    let yen = |t: f64| format!("{} yen", t);
+    [
+        format!("{:<12}{:>14}{:>14}{:>14}", "", "A", "B", "B - A"),
+        row("Distance", a.total_distance, b.total_distance, &distance),
+        row(
+            "Travel time",
+            a.total_travel_time,
+            b.total_travel_time,
+            &format_duration,
+        ),
+        row(
+            "Toll",
+            Some(a.toll().unwrap_or(0.0)),
+            Some(b.toll().unwrap_or(0.0)),
+            &yen,
+        ),
+    ]
+    .join("\n")
+}
+
+async fn handle_compare(compare_args: CompareArgs, client: &ApiClient) -> Result<()> {
+    let header = RequestHeader::new()?;
+    let mut geocoder = GeocodeClient::new(
+        client.clone(),
+        GEOCODE_BASE_URL,
+        RequestHeader::new_with_host(GEOCODE_HOST)?,
+    );
+    let start = geocoder.position(&compare_args.from).await?;
+    let destination = geocoder.position(&compare_args.to).await?;
+
+    let mut a = CalcRouteRequestParam::new(start, destination);
+    a.priority(compare_args.priority_a);
+    if let Some(tollway) = compare_args.tollway_a {
+        a.tollway(tollway);
+    }
+    let mut b = CalcRouteRequestParam::new(start, destination);
+    b.priority(compare_args.priority_b);
+    if let Some(tollway) = compare_args.tollway_b {
+        b.tollway(tollway);
+    }
+
+    let mut summaries = vec![];
+    for result in fetch_routes(client, ROUTE_BASE_URL, &header, vec![a, b], 2).await {
+        summaries.push(result?.summary.unwrap_or_default());
+    }
+    println!(
+        "{}",
+        compare_text(&summaries[0], &summaries[1], Units::Metric)
+    );
+    Ok(())
+}
+
+async fn handle_route(route_args: RouteArgs, client: &ApiClient) -> Result<()> {
+    if route_args.format == Format::Xml {
+        // XML is passed through as is, like --raw
+        let ignored = [
+            ("--simplify", route_args.simplify.is_some()),
+            ("--no-shape", route_args.no_shape),
+            ("--no-guide", route_args.no_guide),
+            ("--fields", route_args.fields.is_some()),
+        ];
+        if let Some((flag, _)) = ignored.iter().find(|(_, set)| *set) {
+            anyhow::bail!(AppError::InvalidInput(format!(
+                "{} cannot be used with --format xml",
+                flag
+            )));
+        }
+    }
+    if let Some(path) = &route_args.replay {
+        if route_args.format == Format::Xml {
+            return Err(AppError::InvalidInput("--replay needs a JSON route".to_string()).into());
+        }
+        let mut obj = replay_route(path)?;
+        transform_route(&mut obj, &route_args)?;
+        return output_route(&obj, &route_args);
+    }
+    if route_args.dry_run {
+        return dry_run(&route_args);
+    }
+
+    let header = RequestHeader::new()?;
+    let mut geocoder = GeocodeClient::new(
+        client.clone(),
+        GEOCODE_BASE_URL,
+        RequestHeader::new_with_host(GEOCODE_HOST)?,
+    );
+    let start = geocoder
+        .position(route_args.from.as_deref().unwrap_or_default())
+        .await?;
+    let destination = geocoder
+        .position(route_args.to.as_deref().unwrap_or_default())
+        .await?;
+    let params = route_params(&route_args, start, destination)?;
+
+    let output = fetch_route_text(client, ROUTE_BASE_URL, &header, &params).await?;
+    if route_args.raw || route_args.format == Format::Xml {
+        return write_output(route_args.file.as_deref(), output);
+    }
+
+    let mut obj = parse_route(&output)?;
+    if route_args.format == Format::TollCompare {
+        fill_missing_toll(&mut obj, client, ROUTE_BASE_URL, &header, &params).await?;
+    }
+    transform_route(&mut obj, &route_args)?;
+    output_route(&obj, &route_args)
+}
+
+/// Request parameters for the route options.
+fn route_params(
+    route_args: &RouteArgs,
+    start: Position,
+    destination: Position,
+) -> Result<CalcRouteRequestParam> {
+    let mut params = CalcRouteRequestParam::new(start, destination);
+    if let Some(car_type) = route_args.car_type {
+        params.car_type(car_type);
+    }
+    if let Some(toll_target) = route_args.toll_target {
+        params.toll_target(toll_target);
+    }
+    if let Some(vehicle_type) = route_args.vehicle_type {
+        params.vehicle_type(vehicle_type);
+    }
+    if route_args.travel {
+        params.travel(true);
+    }
+    if route_args.dangerous_cargo {
+        params.danger(true);
+    }
+    if route_args.daytime_restriction {
+        params.daytime(true);
+    }
+    if route_args.restrict_general_road {
+        params.general_road(true);
+    }
+    if let Some(speed) = route_args.normal_speed {
+        params.normal_speed(speed)?;
+    }
+    if let Some(speed) = route_args.highway_speed {
+        params.highway_speed(speed)?;
+    }
+    if let Some(speed) = route_args.tollway_speed {
+        params.tollway_speed(speed)?;
+    }
+    if let Some(speed) = route_args.ferry_speed {
+        params.ferry_speed(speed)?;
+    }
+    if let Some(angle) = route_args.start_angle {
+        params.start_angle(angle)?;
+    }
+    if let Some(date) = &route_args.date {
+        params.date(resolve_date(date)?);
+    }
+    if let Some(via) = &route_args.via {
+        params.via(via.clone())?;
+    }
+    for area in &route_args.avoid_area {
+        params.impassable_area(&parse_polygon(area)?)?;
+    }
+    for area in &route_args.through_area {
+        params.passable_area(&parse_polygon(area)?)?;
+    }
+    if route_args.respect_oneway || route_args.ignore_oneway {
+        params.regulations(route_args.respect_oneway);
+    }
+    if route_args.avoid_uturn {
+        params.uturn_avoid(true);
+    }
+    if route_args.allow_uturn {
+        params.uturn(true);
+    }
+    if route_args.format == Format::Xml {
+        params.format(OutputFormat::Xml);
+    }
+    Ok(params)
+}
+
+/// Print the request URL and headers without sending anything. Place names are not resolved,
+/// since that would take a geocode request.
+fn dry_run(route_args: &RouteArgs) -> Result<()> {
+    let position = |s: &Option<String>| -> Result<Position> {
+        let s = s.as_deref().unwrap_or_default();
+        anyhow::ensure!(
+            !s.starts_with("name:"),
+            AppError::InvalidInput(format!(
+                "--dry-run needs coordinates, not a place name: {}",
+                s
+            ))
+        );
+        s.parse()
+    };
+    let params = route_params(
+        route_args,
+        position(&route_args.from)?,
+        position(&route_args.to)?,
+    )?;
+    println!("GET {}", route_url(ROUTE_BASE_URL, &params)?);
+    match RequestHeader::new() {
+        Ok(header) => println!("X-RapidAPI-Key: {}", redact_key(&header.api_key)),
+        Err(_) => println!("X-RapidAPI-Key: (RAPID_API_KEY is not set)"),
+    }
+    println!("X-RapidAPI-Host: {}", ROUTE_HOST);
+    Ok(())
+}
+
+/// Apply "--simplify", "--no-shape" and "--no-guide" to a parsed route.
+fn transform_route(obj: &mut RouteResult, route_args: &RouteArgs) -> Result<()> {
+    if let Some(tolerance) = route_args.simplify {
+        obj.simplify(tolerance)?;
+    }
+    if route_args.no_shape {
+        obj.strip_shape();
+    }
+    if route_args.no_guide {
+        obj.guide = None;
+    }
+    Ok(())
+}
+
+/// Render a parsed route and write it to stdout and/or "--output".
+fn output_route(obj: &RouteResult, route_args: &RouteArgs) -> Result<()> {
+    let out_str = render_route(obj, route_args)?;
+    match &route_args.file {
+        Some(file) if route_args.format.is_text() || route_args.highlights => {
+            write_output(Some(file), route_json(obj, route_args)?)?;
+            write_output(None, out_str)
+        }
+        file => write_output(file.as_deref(), out_str),
+    }
+}
+
+/// Write to `file` with a trailing newline, or print to stdout.
+fn write_output(file: Option<&str>, out_str: String) -> Result<()> {
+    if let Some(file) = file {
+        fs::write(file, out_str + "\n")?;
+    } else {
+        println!("{}", out_str);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_should_be_valid() {
+        use clap::CommandFactory;
+        Arguments::command().debug_assert();
+    }
+
+    #[test]
+    fn key_should_be_redacted() {
+        assert_eq!(redact_key("0123456789abcdef"), "************cdef");
+        assert_eq!(redact_key("short"), "*****");
+        let header = RequestHeader {
+            api_key: "0123456789abcdef".to_string(),
+            api_host: "host".to_string(),
+        };
+        assert!(!format!("{:?}", header).contains("0123456789"));
+    }
+
+    #[test]
+    fn input_error_should_exit_with_2() {
+        let e = "139.7".parse::<Position>().unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        let e = CalcRouteRequestParam::default()
+            .start_angle(400)
+            .unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        assert_eq!(exit_code(&anyhow::anyhow!("other")), 1);
+        assert_eq!(
+            error_json(&anyhow::anyhow!("other")),
+            serde_json::json!({"error": "other", "message": "other"})
+        );
+    }
+
+    #[test]
+    fn relative_date_should_be_resolved() {
+        let now = chrono::NaiveDateTime::parse_from_str("20221204_100000", DATE_FORMAT).unwrap();
+        assert_eq!(resolve_date_at("now", now).unwrap(), "20221204_100000");
+        assert_eq!(resolve_date_at("+30m", now).unwrap(), "20221204_103000");
+        assert_eq!(resolve_date_at("+15h", now).unwrap(), "20221205_010000");
+        assert_eq!(
+            resolve_date_at("20230101_083000", now).unwrap(),
+            "20230101_083000"
+        );
+        assert!(resolve_date_at("+2d", now).is_err());
+        assert!(resolve_date_at("+-5m", now).is_err());
+        assert!(resolve_date_at("++5m", now).is_err());
+        assert!(resolve_date_at("+m", now).is_err());
+        assert!(resolve_date_at("+99999999999h", now).is_err());
+        assert!(resolve_date_at("+9999999999999999m", now).is_err());
+        assert!(resolve_date_at("+3分", now).is_err());
+        assert!(resolve_date_at("2023-01-01", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn route_should_be_fetched() {
+        use wiremock::matchers::{header, method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/calcroute"))
+            .and(query_param("start", "139.700000,35.600000"))
+            .and(header("X-RapidAPI-Key", "test-key"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"status": "OK", "summary": {"totalDistance": 1500.0, "totalTravelTime": 300.0}}"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let request_header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let params = CalcRouteRequestParam::new(
+            Position {
+                longitude: 139.7,
+                latitude: 35.6,
+            },
+            Position {
+                longitude: 139.8,
+                latitude: 35.7,
+            },
+        );
+        let base_url = format!("{}/calcroute", server.uri());
+        let client = ApiClient::new(reqwest::Client::new());
+        let route = fetch_route(&client, &base_url, &request_header, &params)
+            .await
+            .unwrap();
+        assert_eq!(route.status, Some("OK".to_string()));
+        let summary = route.summary.unwrap();
+        assert_eq!(summary.total_distance, Some(1500.0));
+        assert_eq!(summary.total_travel_time, Some(300.0));
+    }
+
+    #[test]
+    fn non_ok_status_should_fail() {
+        let full = r#"{
+            "routeId": "1", "routeResultId": "abc", "status": "OK",
+            "summary": {"totalDistance": 1500.0, "totalTravelTime": 300.0, "totalToll": {"toll": 0.0}},
+            "guide": [{"type": 1, "guidePoints": [{"lon": 139.7, "lat": 35.6}],
+                "guideInfo": {"distance": 1500.0, "travelTime": 300.0,
+                    "shapePoints": [{"lon": 139.7, "lat": 35.6, "el": 3}]}},
+                {"type": 2, "guidePoints": [{"lon": 139.8, "lat": 35.7}]}]
+        }"#;
+        assert!(parse_route(full).is_ok());
+        assert!(parse_route(r#"{"status": "ok"}"#).is_ok());
+        assert!(parse_route(r#"{"summary": {}}"#).is_ok());
+        let e = parse_route(r#"{"status": "SOMETHING_NEW"}"#).unwrap_err();
+        assert_eq!(exit_code(&e), 7);
+        assert_eq!(error_json(&e)["error"], "route_status");
+        assert_eq!(e.to_string(), "route search failed: SOMETHING_NEW");
+    }
+
+    #[test]
+    fn replay_should_parse_saved_route() {
+        let path = std::env::temp_dir().join(format!(
+            "mapfanrs_replay_should_parse_saved_route_{}.json",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            r#"{"status": "OK", "summary": {"totalDistance": 1500.0, "totalTravelTime": 300.0}}"#,
+        )
+        .unwrap();
+        let route = replay_route(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(route.summary.unwrap().total_distance, Some(1500.0));
+        assert!(replay_route("no/such/route.json").is_err());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_should_exit_with_4() {
+        use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let e = fetch_route(
+            &ApiClient::new(reqwest::Client::new()),
+            &server.uri(),
+            &header,
+            &CalcRouteRequestParam::new_with_result_id("id".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(exit_code(&e), 4);
+        let json = error_json(&e);
+        assert_eq!(json["error"], "rate_limited");
+        assert_eq!(json["status"], 429);
+        assert!(json["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("rate limited: "));
+    }
+
+    #[tokio::test]
+    async fn error_body_should_be_surfaced() {
+        use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(
+                ResponseTemplate::new(400)
+                    .set_body_string(r#"{"message": "start coordinate out of service area"}"#),
+            )
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let e = fetch_route(
+            &ApiClient::new(reqwest::Client::new()),
+            &server.uri(),
+            &header,
+            &CalcRouteRequestParam::new_with_result_id("id".to_string()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(exit_code(&e), 5);
+        assert!(e
+            .to_string()
+            .ends_with("API error: start coordinate out of service area"));
+    }
+
+    #[tokio::test]
+    async fn names_should_be_geocoded_once() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/addr"))
+            .and(query_param("addr", "東京駅"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"results": [{"name": "東京駅", "lon": 139.767, "lat": 35.681}]}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/addr"))
+            .and(query_param("addr", "nowhere"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"results": []}"#))
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let base_url = format!("{}/addr", server.uri());
+        let client = ApiClient::new(reqwest::Client::new());
+        let mut geocoder = GeocodeClient::new(client.clone(), &base_url, header);
+        let expected = Position {
+            longitude: 139.767,
+            latitude: 35.681,
+        };
+        assert_eq!(geocoder.position("name:東京駅").await.unwrap(), expected);
+        assert_eq!(geocoder.position("name:東京駅").await.unwrap(), expected);
+        assert_eq!(
+            geocoder.position("139.7,35.6").await.unwrap(),
+            Position {
+                longitude: 139.7,
+                latitude: 35.6
+            }
+        );
+        let e = geocoder.position("name:nowhere").await.unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        assert_eq!(
+            e.to_string(),
+            "invalid input: no geocode result for: nowhere"
+        );
+        assert_eq!(client.stats.to_text(), "2 API requests made (1 cache hits)");
+    }
+
+    #[tokio::test]
+    async fn dry_run_should_not_send_requests() {
+        let client = ApiClient::new(reqwest::Client::new());
+        let args = ["route", "-f", "139.7,35.6", "-t", "139.8,35.7", "--dry-run"];
+        handle_route(RouteArgs::try_parse_from(args).unwrap(), &client)
+            .await
+            .unwrap();
+        let args = ["route", "-f", "name:Tokyo", "-t", "139.8,35.7", "--dry-run"];
+        let e = handle_route(RouteArgs::try_parse_from(args).unwrap(), &client)
+            .await
+            .unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        assert_eq!(client.stats.to_text(), "0 API requests made (0 cache hits)");
+    }
+
+    #[tokio::test]
+    async fn matrix_should_count_requests() {
+        use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"status": "OK"}"#))
+            .expect(6)
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let client = ApiClient::new(reqwest::Client::new());
+        let points = parse_points("139.7,35.6;139.8,35.7;139.9,35.8").unwrap();
+        let results = fetch_matrix(&client, &server.uri(), &header, &points, 2).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(client.stats.to_text(), "6 API requests made (0 cache hits)");
+    }
+
+    #[tokio::test]
+    async fn missing_toll_should_be_fetched() {
+        use wiremock::matchers::{method, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("etc", "1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"{"status": "OK", "summary": {"totalTollEtc": {"toll": 1000.0}}}"#,
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let mut route =
+            parse_route(r#"{"status": "OK", "summary": {"totalToll": {"toll": 1200.0}}}"#).unwrap();
+        let start = Position {
+            longitude: 139.7,
+            latitude: 35.6,
+        };
+        let params = CalcRouteRequestParam::new(start, start);
+        let client = ApiClient::new(reqwest::Client::new());
+        fill_missing_toll(&mut route, &client, &server.uri(), &header, &params)
+            .await
+            .unwrap();
+        let summary = route.summary.unwrap();
+        assert_eq!(
+            (summary.toll(), summary.toll_etc()),
+            (Some(1200.0), Some(1000.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_should_report_progress() {
+        use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_string(r#"{"status": "OK"}"#))
+            .mount(&server)
+            .await;
+
+        let header = RequestHeader {
+            api_key: "test-key".to_string(),
+            api_host: "localhost".to_string(),
+        };
+        let params = (0..3)
+            .map(|i| CalcRouteRequestParam::new_with_result_id(i.to_string()))
+            .collect();
+        let mut progress = vec![];
+        let results = fetch_routes_with_progress(
+            &ApiClient::new(reqwest::Client::new()),
+            &server.uri(),
+            &header,
+            params,
+            2,
+            |completed, total, result| progress.push((completed, total, result.is_ok())),
+        )
+        .await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(progress, vec![(1, 3, true), (2, 3, true), (3, 3, true)]);
+    }
+
+    #[test]
+    fn onoff_should_be_number() {
+        let params = CalcRouteRequestParam {
+            tollroad: Some(OnOff::On),
+            ..Default::default()
+        };
         assert_eq!(
             params.to_params(),
             vec![
@@ -789,6 +3569,70 @@ mod tests {
             ]
         );
     }
+    #[test]
+    fn car_and_vehicle_type_should_be_number() {
+        let args = [
+            "route",
+            "-f",
+            "0,0",
+            "-t",
+            "0,0",
+            "--car-type",
+            "super-big",
+            "--vehicle-type",
+            "big-cargo",
+        ];
+        let args = RouteArgs::try_parse_from(args).unwrap();
+        let origin = Position {
+            longitude: 0.0,
+            latitude: 0.0,
+        };
+        assert_eq!(
+            route_params(&args, origin, origin).unwrap().to_params()[2..],
+            vec![
+                ("cartype".to_string(), "4".to_string()),
+                ("vehicletype".to_string(), "2".to_string())
+            ]
+        );
+
+        let args = RouteArgs::try_parse_from(["route", "-f", "0,0", "-t", "0,0"]).unwrap();
+        let params = route_params(&args, origin, origin).unwrap().to_params();
+        assert_eq!(params.len(), 2);
+    }
+
+    #[test]
+    fn restrictions_should_be_one() {
+        let mut params = CalcRouteRequestParam::default();
+        params.danger(true).daytime(true).general_road(true);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("danger".to_string(), "1".to_string()),
+                ("daytime".to_string(), "1".to_string()),
+                ("generalroad".to_string(), "1".to_string())
+            ]
+        );
+        let args = [
+            "route",
+            "-f",
+            "0,0",
+            "-t",
+            "0,0",
+            "--dangerous-cargo",
+            "--daytime-restriction",
+            "--restrict-general-road",
+        ];
+        let args = RouteArgs::try_parse_from(args).unwrap();
+        let origin = Position {
+            longitude: 0.0,
+            latitude: 0.0,
+        };
+        let from_args = route_params(&args, origin, origin).unwrap().to_params();
+        assert_eq!(from_args[2..], params.to_params()[2..]);
+    }
+
     #[test]
     fn date_should_be_date() {
         let params = CalcRouteRequestParam {
@@ -804,5 +3648,582 @@ mod tests {
             ]
         );
     }
+    #[test]
+    fn road_type_should_be_decoded() {
+        assert_eq!(RoadType::from_code(101), Some(RoadType::Highway));
+        assert_eq!(RoadType::from_code(108), Some(RoadType::OtherToll));
+        assert_eq!(RoadType::from_code(11), None);
+        assert_eq!(RoadType::from_code(250), None);
+        assert_eq!(RoadType::from_code(300), None);
+
+        let info = |json: &str| serde_json::from_str::<GuideInfo>(json).unwrap();
+        assert_eq!(
+            info(r#"{"roadType": 102}"#).road_type_enum(),
+            Some(RoadType::CityHighway)
+        );
+        assert_eq!(info(r#"{"roadType": 100}"#).road_type_enum(), None);
+        assert_eq!(info("{}").road_type_enum(), None);
+    }
+    #[test]
+    fn bounding_box_should_cover_all_points() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"guide": [
+                {"type": 1, "guidePoints": [{"lon": 139.70, "lat": 35.60}],
+                 "guideInfo": {"shapePoints": [{"lon": 139.71, "lat": 35.65}, {"lon": 139.75, "lat": 35.62}]}},
+                {"type": 2, "guidePoints": [{"lon": 139.72, "lat": 35.68}]}
+            ]}"#,
+        )
+        .unwrap();
+        let (min, max) = route.bounding_box().unwrap();
+        assert_eq!((min.lon, min.lat), (Some(139.70), Some(35.60)));
+        assert_eq!((max.lon, max.lat), (Some(139.75), Some(35.68)));
+
+        let empty: RouteResult = serde_json::from_str("{}").unwrap();
+        assert!(empty.bounding_box().is_none());
+    }
+    #[test]
+    fn areas_should_be_pipe_delimited() {
+        let mut params = CalcRouteRequestParam::default();
+        params
+            .impassable_area(&parse_polygon("139.7,35.6;139.8,35.6;139.8,35.7").unwrap())
+            .unwrap()
+            .impassable_area(&parse_polygon("139.1,35.1;139.2,35.1;139.2,35.2;139.1,35.2").unwrap())
+            .unwrap();
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                (
+                    "impassablearea".to_string(),
+                    "139.700000,35.600000,139.800000,35.600000,139.800000,35.700000|\
+                     139.100000,35.100000,139.200000,35.100000,139.200000,35.200000,139.100000,35.200000"
+                        .to_string()
+                )
+            ]
+        );
+        assert!(params
+            .passable_area(&parse_polygon("139.7,35.6;139.8,35.6").unwrap())
+            .is_err());
+    }
+    #[test]
+    fn summary_should_convert_units() {
+        let summary: RouteSummary =
+            serde_json::from_str(r#"{"totalDistance": 12345.0, "totalTravelTime": 3725.0}"#)
+                .unwrap();
+        assert_eq!(summary.total_distance_km(), Some(12.345));
+        assert_eq!(summary.total_travel_time_minutes(), Some(3725.0 / 60.0));
+        assert_eq!(summary.total_travel_time_hms(), Some((1, 2, 5)));
+    }
+    #[test]
+    fn imperial_should_be_labeled_in_miles() {
+        assert_eq!(Units::Metric.distance(1609.344), "1.6 km");
+        assert_eq!(Units::Imperial.distance(1609.344), "1.0 mi");
+        assert_eq!(Units::Imperial.speed(100.0), "62.1 mph");
+    }
+    #[test]
+    fn polyline_should_be_encoded() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"guide": [{"guideInfo": {"shapePoints": [
+                {"lon": -120.2, "lat": 38.5},
+                {"lon": -120.95, "lat": 40.7},
+                {"lon": -126.453, "lat": 43.252}
+            ]}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(route.encoded_polyline(5), "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+    }
+    #[test]
+    fn simplify_should_keep_waypoints() {
+        let mut route: RouteResult = serde_json::from_str(
+            r#"{"guide": [
+                {"type": 1, "guideInfo": {"shapeIndexFirst": {"shapePointsIndex": 0},
+                    "shapeIndexLast": {"shapePointsIndex": 2}, "shapePoints": [
+                    {"lon": 139.7, "lat": 35.6}, {"lon": 139.701, "lat": 35.6},
+                    {"lon": 139.702, "lat": 35.6}
+                ]}},
+                {"type": 3, "guideInfo": {"shapePoints": [
+                    {"lon": 139.703, "lat": 35.6}, {"lon": 139.704, "lat": 35.6},
+                    {"lon": 139.705, "lat": 35.6}, {"lon": 139.706, "lat": 35.61}
+                ]}},
+                {"type": 2}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(route.simplify(-1.0).is_err());
+        assert!(route.simplify(f64::NAN).is_err());
+        route.simplify(10.0).unwrap();
+        assert_eq!(
+            route.shape_points(),
+            vec![
+                (139.7, 35.6),
+                (139.703, 35.6),
+                (139.705, 35.6),
+                (139.706, 35.61)
+            ]
+        );
+        let info = route.guide.as_ref().unwrap()[0]
+            .guide_info
+            .as_ref()
+            .unwrap();
+        assert!(info.shape_index_first.is_none() && info.shape_index_last.is_none());
+    }
+    #[test]
+    fn start_and_destination_or_result_id_should_be_set() {
+        let start = Position {
+            longitude: 139.7,
+            latitude: 35.6,
+        };
+        assert!(CalcRouteRequestParam::new(start, start).validate().is_ok());
+        assert!(CalcRouteRequestParam::new_with_result_id("id".to_string())
+            .validate()
+            .is_ok());
+
+        let e = CalcRouteRequestParam::default().validate().unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        let mut p = CalcRouteRequestParam::new(start, start);
+        p.destination = String::new();
+        assert!(p.validate().is_err());
+        p.routeresultid = Some("id".to_string());
+        assert!(p.validate().is_err());
+        assert!(route_url(ROUTE_BASE_URL, &CalcRouteRequestParam::default()).is_err());
+    }
+
+    #[test]
+    fn start_angle_should_be_validated() {
+        let mut params = CalcRouteRequestParam::default();
+        assert!(params.start_angle(360).is_err());
+        assert!(params.start_angle(-1).is_err());
+        params.start_angle(90).unwrap();
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("startangle".to_string(), "90".to_string())
+            ]
+        );
+    }
+    #[test]
+    fn via_points_should_be_pipe_delimited() {
+        let mut params = CalcRouteRequestParam::default();
+        params.via_points(vec![
+            ViaPoint {
+                position: Position {
+                    longitude: 139.7,
+                    latitude: 35.6,
+                },
+                via_type: Some(1),
+                priority: Some(2),
+            },
+            ViaPoint {
+                position: Position {
+                    longitude: 139.8,
+                    latitude: 35.7,
+                },
+                via_type: None,
+                priority: None,
+            },
+        ]);
+        assert_eq!(
+            params.via,
+            Some("139.700000,35.600000,1,2|139.800000,35.700000".to_string())
+        );
+
+        params
+            .via("139.7,35.6,,2|139.8,35.7,1".to_string())
+            .unwrap();
+        assert_eq!(
+            params.via,
+            Some("139.700000,35.600000,,2|139.800000,35.700000,1".to_string())
+        );
+        assert!(params.via("139.7".to_string()).is_err());
+    }
+    #[test]
+    fn unknown_code_should_not_fail_parse() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"guide": [{"type": 99, "guideInfo": {"guideDirection": 200, "shape": [4, 77]}}]}"#,
+        )
+        .unwrap();
+        let guide = &route.guide.as_ref().unwrap()[0];
+        assert_eq!(guide.type_, Some(Code::Unknown(99)));
+        assert_eq!(guide.guide_type(), None);
+        let info = guide.guide_info.as_ref().unwrap();
+        assert_eq!(info.guide_direction, Some(Code::Unknown(200)));
+        assert_eq!(info.shape_types(), vec![&ShapeType::Road]);
+        assert_eq!(
+            serde_json::to_string(&info.shape).unwrap(),
+            "[4,77]".to_string()
+        );
+    }
+    #[test]
+    fn sections_should_follow_stops() {
+        let summary: RouteSummary =
+            serde_json::from_str(r#"{"totalTravelTime": 900.0, "sectionTime": [300.0, 600.0]}"#)
+                .unwrap();
+        let stops = ["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(
+            summary.sections_text(&stops),
+            "1\ta -> b\t5.0 min\n2\tb -> c\t10.0 min"
+        );
+
+        let summary: RouteSummary = serde_json::from_str(r#"{"totalTravelTime": 90.0}"#).unwrap();
+        assert_eq!(summary.sections_text(&stops[..2]), "1\ta -> b\t1.5 min");
+    }
+    #[test]
+    fn unknown_fields_should_be_kept() {
+        let body = r#"{"newTop":1,"summary":{"totalDistance":1.0,"co2":2},"guide":[{"guideInfo":{"lane":3}},{"guideInfo":{"lane":4}}]}"#;
+        let route = parse_route(body).unwrap();
+        assert_eq!(
+            route.unknown_fields(),
+            vec!["guide[].guideInfo.lane", "newTop", "summary.co2"]
+        );
+        let value = serde_json::to_value(&route).unwrap();
+        assert_eq!(value["newTop"], 1);
+        assert_eq!(value["summary"]["co2"], 2);
+        assert_eq!(value["guide"][1]["guideInfo"]["lane"], 4);
+    }
+    #[test]
+    fn points_should_be_parsed() {
+        assert_eq!(
+            parse_points("139.7,35.6; 139.8,35.7\n139.9,35.8").unwrap(),
+            vec![
+                Position {
+                    longitude: 139.7,
+                    latitude: 35.6
+                },
+                Position {
+                    longitude: 139.8,
+                    latitude: 35.7
+                },
+                Position {
+                    longitude: 139.9,
+                    latitude: 35.8
+                },
+            ]
+        );
+    }
+    #[test]
+    fn matrix_should_be_csv() {
+        let points = parse_points("139.7,35.6;139.8,35.7").unwrap();
+        assert_eq!(matrix_pairs(2), vec![(0, 1), (1, 0)]);
+        let results = vec![
+            parse_route(r#"{"summary": {"totalDistance": 1500.0, "totalTravelTime": 300.0}}"#),
+            Err(anyhow::anyhow!("failed")),
+        ];
+        assert_eq!(
+            matrix_text(&points, &results),
+            [
+                r#"travel_time_min,"139.7,35.6","139.8,35.7""#,
+                r#""139.7,35.6",0.0,5.0"#,
+                r#""139.8,35.7",,0.0"#,
+                "",
+                r#"distance_km,"139.7,35.6","139.8,35.7""#,
+                r#""139.7,35.6",0.0,1.5"#,
+                r#""139.8,35.7",,0.0"#,
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn toll_compare_should_show_savings() {
+        let summary: RouteSummary = serde_json::from_str(
+            r#"{"totalToll": {"toll": 1200.0}, "totalTollEtc": {"toll": 1000.0}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            summary.toll_compare_text(),
+            "Toll (cash):  1200 yen\nToll (ETC):   1000 yen\nETC savings:  200 yen"
+        );
+        assert!(RouteSummary::default()
+            .toll_compare_text()
+            .ends_with("ETC savings:  0 yen"));
+    }
+    #[test]
+    fn speeds_should_be_positive() {
+        let mut params = CalcRouteRequestParam::default();
+        assert!(params.normal_speed(0.0).is_err());
+        params
+            .normal_speed(40.0)
+            .unwrap()
+            .highway_speed(90.5)
+            .unwrap();
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("normalspeed".to_string(), "40".to_string()),
+                ("highwayspeed".to_string(), "90.5".to_string())
+            ]
+        );
+    }
+    #[test]
+    fn toll_target_should_be_number() {
+        let mut params = CalcRouteRequestParam::default();
+        params.toll_target(TollTarget::NormalPlusEtcDiscount);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("tolltarget".to_string(), "1".to_string())
+            ]
+        );
+    }
+    #[test]
+    fn departure_should_be_parsed() {
+        let summary: RouteSummary = serde_json::from_str(
+            r#"{"totalTravelTime": 5400.0, "departureTime": {"date": "20221204", "time": "233000"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            summary.departure_time.as_ref().unwrap().to_naive(),
+            chrono::NaiveDateTime::parse_from_str("20221204_233000", DATE_FORMAT).ok()
+        );
+        assert!(summary
+            .to_text(Units::Metric)
+            .contains("Arrival:     2022-12-05 01:00:00"));
+
+        let broken: DateTime = serde_json::from_str(r#"{"date": "20221204"}"#).unwrap();
+        assert_eq!(broken.to_naive(), None);
+    }
+    #[test]
+    fn regulations_should_be_number() {
+        let mut params = CalcRouteRequestParam::default();
+        params.regulations(false);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("regulations".to_string(), "0".to_string())
+            ]
+        );
+    }
+    #[test]
+    fn travel_should_be_number() {
+        let mut params = CalcRouteRequestParam::default();
+        params.priority(Priority::DistanceFirst).travel(true);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("priority".to_string(), "1".to_string()),
+                ("travel".to_string(), "1".to_string())
+            ]
+        );
+        let args = RouteArgs::try_parse_from(["route", "-f", "0,0", "-t", "0,0", "--scenic"]);
+        assert!(args.unwrap().travel);
+    }
+
+    #[test]
+    fn uturn_should_be_number() {
+        let mut params = CalcRouteRequestParam::default();
+        params.uturn_avoid(true).uturn(false).regulations(true);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("regulations".to_string(), "1".to_string()),
+                ("uturnavoid".to_string(), "1".to_string()),
+                ("uturn".to_string(), "0".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn maneuvers_should_sum_to_summary() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"summary": {"totalDistance": 1234.6, "totalTravelTime": 181.0},
+                "guide": [
+                {"type": 1, "guideInfo": {"distance": 200.2, "travelTime": 30.5}},
+                {"type": 0, "guideInfo": {"guideDirection": 5, "distance": 1034.4, "travelTime": 150.5,
+                    "guideCrossing": {"name": "渋谷駅前"}, "guideRoad": {"name": "国道246号"}}},
+                {"type": 2}
+            ]}"#,
+        )
+        .unwrap();
+        let maneuvers: Vec<Maneuver> = route.maneuvers().collect();
+        assert_eq!(maneuvers.len(), 3);
+        assert_eq!(maneuvers[0].instruction, "Start");
+        assert_eq!(maneuvers[1].direction, Some(&GuideDirection::Right));
+        assert_eq!(
+            maneuvers[1].instruction,
+            "Turn right at 渋谷駅前 onto 国道246号"
+        );
+        assert_eq!(maneuvers[2].guide_type, Some(&GuideType::Goal));
+        let last = maneuvers.last().unwrap();
+        let summary = route.summary.as_ref().unwrap();
+        assert!((last.cumulative_distance - summary.total_distance.unwrap()).abs() < 1.0);
+        assert!((last.cumulative_time - summary.total_travel_time.unwrap()).abs() < 1.0);
+    }
+    #[test]
+    fn distance_should_be_meters() {
+        // 0.001 degree of longitude at 35.6N
+        assert!((distance_m((139.7, 35.6), (139.701, 35.6)) - 90.4).abs() < 0.1);
+        assert!((distance_m((0.0, 0.0), (0.0, 1.0)) - 111_195.0).abs() < 1.0);
+        assert_eq!(distance_m((139.7, 35.6), (139.7, 35.6)), 0.0);
+    }
+
+    #[test]
+    fn elevation_should_sum_climb_and_descent() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"guide": [{"guideInfo": {"shapePoints": [
+                {"lon": 139.7, "lat": 35.6, "el": 10}, {"lon": 139.701, "lat": 35.6, "el": 20},
+                {"lon": 139.702, "lat": 35.6, "el": 15}, {"lon": 139.703, "lat": 35.6, "el": 40},
+                {"lon": 139.704, "lat": 35.6, "el": 15}, {"lon": 139.705, "lat": 35.6}
+            ]}}]}"#,
+        )
+        .unwrap();
+        let raw = route.elevation(1).unwrap();
+        assert_eq!((raw.ascent, raw.descent), (35.0, 30.0));
+        // 25m over about 90m
+        assert!((27.0..28.0).contains(&raw.max_grade));
+        let smoothed = route.elevation(3).unwrap();
+        assert!(smoothed.max_grade < raw.max_grade);
+        assert_eq!(
+            smoothed.to_text(Units::Metric).lines().next(),
+            Some("Ascent:      14 m")
+        );
+        let flat: RouteResult = serde_json::from_str(
+            r#"{"guide": [{"guideInfo": {"shapePoints": [{"lon": 139.7, "lat": 35.6}]}}]}"#,
+        )
+        .unwrap();
+        assert!(flat.elevation(1).is_none());
+    }
+
+    #[test]
+    fn fields_should_be_projected() {
+        let json = serde_json::json!({
+            "status": "OK",
+            "summary": {"totalDistance": 1500.0, "totalTravelTime": 300.0},
+            "guide": [
+                {"type": 1, "guideInfo": {"guideCrossing": {"name": "A"}, "distance": 10.0}},
+                {"type": 2, "guideInfo": null}
+            ]
+        });
+        assert_eq!(
+            project_fields(
+                &json,
+                "summary.totalTravelTime,guide.guideInfo.guideCrossing.name"
+            )
+            .unwrap(),
+            serde_json::json!({
+                "summary": {"totalTravelTime": 300.0},
+                "guide": [
+                    {"guideInfo": {"guideCrossing": {"name": "A"}}},
+                    {"guideInfo": null}
+                ]
+            })
+        );
+        let e = project_fields(&json, "summary.distance").unwrap_err();
+        assert_eq!(exit_code(&e), 2);
+        assert!(e
+            .to_string()
+            .ends_with("valid top-level keys are: guide, status, summary"));
+    }
+
+    #[tokio::test]
+    async fn unprocessed_output_should_reject_filters() {
+        let route = ["route", "-f", "0,0", "-t", "0,0"];
+        assert!(RouteArgs::try_parse_from(route.iter().chain(&["--raw"])).is_ok());
+        for flag in [
+            "--no-shape",
+            "--no-guide",
+            "--simplify=1",
+            "--fields=summary",
+        ] {
+            let raw = RouteArgs::try_parse_from(route.iter().chain(&["--raw", flag]));
+            assert!(raw.is_err(), "{}", flag);
+            let xml = ["--format", "xml", flag, "--dry-run"];
+            let args = RouteArgs::try_parse_from(route.iter().chain(&xml)).unwrap();
+            let client = ApiClient::new(reqwest::Client::new());
+            let e = handle_route(args, &client).await.unwrap_err();
+            assert_eq!(exit_code(&e), 2);
+        }
+    }
+
+    #[test]
+    fn shape_and_guide_should_be_dropped() {
+        let body = r#"{"summary": {"totalDistance": 1500.0}, "guide": [{"type": 1,
+            "guideInfo": {"distance": 1500.0, "shapePoints": [{"lon": 139.7, "lat": 35.6}]}}]}"#;
+        let mut route = parse_route(body).unwrap();
+        route.strip_shape();
+        let json = serde_json::to_value(&route).unwrap();
+        assert!(json["guide"][0]["guideInfo"]["shapePoints"].is_null());
+        assert_eq!(json["guide"][0]["guideInfo"]["distance"], 1500.0);
+
+        let mut route = parse_route(body).unwrap();
+        let args =
+            RouteArgs::try_parse_from(["route", "--replay", "r.json", "--no-guide"]).unwrap();
+        transform_route(&mut route, &args).unwrap();
+        assert!(route.guide.is_none());
+        assert_eq!(route.summary.unwrap().total_distance, Some(1500.0));
+    }
+
+    #[test]
+    fn kml_should_be_lon_lat_alt() {
+        let route: RouteResult = serde_json::from_str(
+            r#"{"guide": [
+                {"type": 1, "guidePoints": [{"lon": 139.7, "lat": 35.6}],
+                 "guideInfo": {"guideCrossing": {"name": "A&B"},
+                    "shapePoints": [{"lon": 139.7, "lat": 35.6, "el": 12}, {"lon": 139.8, "lat": 35.7}]}},
+                {"type": 2, "guidePoints": [{"lon": 139.8, "lat": 35.7}]}
+            ]}"#,
+        )
+        .unwrap();
+        let kml = route.to_kml();
+        assert!(kml.contains("<coordinates>139.7,35.6,12 139.8,35.7,0</coordinates>"));
+        assert!(kml.contains("<name>Start: A&amp;B</name><Point><coordinates>139.7,35.6,0"));
+        assert!(kml.contains("<name>Goal</name><Point><coordinates>139.8,35.7,0"));
+    }
+    #[test]
+    fn compare_should_show_delta() {
+        let a: RouteSummary = serde_json::from_str(
+            r#"{"totalDistance": 12000.0, "totalTravelTime": 1500.0, "totalToll": {"toll": 1200.0}}"#,
+        )
+        .unwrap();
+        let b: RouteSummary =
+            serde_json::from_str(r#"{"totalDistance": 10000.0, "totalTravelTime": 1860.0}"#)
+                .unwrap();
+        let text = compare_text(&a, &b, Units::Metric);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[1].ends_with("12.0 km       10.0 km       -2.0 km"));
+        assert!(lines[2].ends_with("0:25:00       0:31:00      +0:06:00"));
+        assert!(lines[3].ends_with("1200 yen         0 yen     -1200 yen"));
+    }
+    #[test]
+    fn position_should_keep_precision() {
+        let start: Position = "139.7671248,35.6812362".parse().unwrap();
+        assert_eq!(start.longitude, 139.7671248);
+        let params = CalcRouteRequestParam::new(start, start);
+        assert_eq!(params.start, "139.767125,35.681236");
+    }
+    #[test]
+    fn position_should_have_fixed_decimals() {
+        let start = Position {
+            longitude: 139.7,
+            latitude: 35.68,
+        };
+        let params = CalcRouteRequestParam::new(start, start);
+        assert_eq!(params.start, "139.700000,35.680000");
+    }
+    #[test]
+    fn fmt_should_be_plain_string() {
+        let mut params = CalcRouteRequestParam::default();
+        params.format(OutputFormat::Xml);
+        assert_eq!(
+            params.to_params(),
+            vec![
+                ("start".to_string(), "".to_string()),
+                ("destination".to_string(), "".to_string()),
+                ("fmt".to_string(), "xml".to_string())
+            ]
+        );
+    }
 
 }